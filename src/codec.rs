@@ -0,0 +1,550 @@
+use anyhow::Ok as AOk;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// CRC-32 (IEEE), chainable like zlib's `crc32(crc, buf)`; start with 0.
+pub fn crc32(crc: u32, b: &[u8]) -> u32 {
+    const T: [u32; 16] = [
+        0x00000000, 0x1DB71064, 0x3B6E20C8, 0x26D930AC, 0x76DC4190, 0x6B6B51F4, 0x4DB26158,
+        0x5005713C, 0xEDB88320, 0xF00F9344, 0xD6D6A3E8, 0xCB61B38C, 0x9B64C2B0, 0x86D3D2D4,
+        0xA00AE278, 0xBDBDF21C,
+    ];
+    let mut crc = !crc;
+    for &x in b {
+        crc = (crc >> 4) ^ T[((crc ^ u32::from(x)) & 0xF) as usize];
+        crc = (crc >> 4) ^ T[((crc ^ (u32::from(x) >> 4)) & 0xF) as usize];
+    }
+    !crc
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RtcDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+impl RtcDateTime {
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+        Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+    /// Parses the `YYYY-MM-DD HH:MM:SS` form produced by `Display`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (date, time) = s.trim().split_once([' ', 'T'])?;
+        let mut d = date.splitn(3, '-');
+        let mut t = time.splitn(3, ':');
+        let dt = Self::new(
+            d.next()?.parse().ok()?,
+            d.next()?.parse().ok()?,
+            d.next()?.parse().ok()?,
+            t.next()?.parse().ok()?,
+            t.next()?.parse().ok()?,
+            t.next()?.parse().ok()?,
+        );
+        let valid = (2000..=2099).contains(&dt.year)
+            && (1..=12).contains(&dt.month)
+            && (1..=31).contains(&dt.day)
+            && dt.hour < 24
+            && dt.minute < 60
+            && dt.second < 60;
+        valid.then_some(dt)
+    }
+    // days_from_civil / civil_from_days from http://howardhinnant.github.io/date_algorithms.html
+    /// `None` if a field is out of range (e.g. garbage from the RTC) or the
+    /// time is outside what a `u32` holds, 1970 to 2106.
+    pub fn to_unix(&self) -> Option<u32> {
+        let valid = (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60;
+        if !valid {
+            return None;
+        }
+        let (m, d) = (u32::from(self.month), u32::from(self.day));
+        let y = u32::from(self.year).checked_sub(u32::from(m <= 2))?;
+        let era = y / 400;
+        let yoe = y - era * 400;
+        let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = (era * 146097 + doe).checked_sub(719468)?;
+        days.checked_mul(86400)?.checked_add(
+            u32::from(self.hour) * 3600 + u32::from(self.minute) * 60 + u32::from(self.second),
+        )
+    }
+    pub fn from_unix(ts: u32) -> Self {
+        let (days, secs) = (ts / 86400 + 719468, ts % 86400);
+        let era = days / 146097;
+        let doe = days - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u32::from(month <= 2);
+        Self::new(
+            year as u16,
+            month as u8,
+            day as u8,
+            (secs / 3600) as u8,
+            (secs % 3600 / 60) as u8,
+            (secs % 60) as u8,
+        )
+    }
+}
+impl std::fmt::Display for RtcDateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        } = self;
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}"
+        )
+    }
+}
+/// Streaming gzip encoder sized for the ESP32-C3: greedy LZ77 over a 4 KiB
+/// window with one hash slot per bucket, emitted as a single fixed-Huffman
+/// deflate block. Uses ~17 KiB of RAM, compared to ~300 KiB for miniz.
+pub struct GzipEncoder<W: FnMut(&[u8]) -> Result<()>> {
+    out: W,
+    buf: Vec<u8>,
+    pos: usize,
+    head: Vec<u16>,
+    bits: u64,
+    nbits: u32,
+    obuf: Vec<u8>,
+    crc: u32,
+    size: u32,
+}
+impl<W: FnMut(&[u8]) -> Result<()>> GzipEncoder<W> {
+    const WINDOW: usize = 4096;
+    const HASH_BITS: u32 = 12;
+    const MIN_MATCH: usize = 3;
+    const MAX_MATCH: usize = 258;
+    const LEN_BASE: [u16; 29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+        131, 163, 195, 227, 258,
+    ];
+    const LEN_EXTRA: [u8; 29] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+    ];
+    const DIST_BASE: [u16; 30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+        2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+    ];
+    const DIST_EXTRA: [u8; 30] = [
+        0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12,
+        13, 13,
+    ];
+    pub fn new(out: W) -> Result<Self> {
+        let mut s = Self {
+            out,
+            buf: Vec::with_capacity(2 * Self::WINDOW),
+            pos: 0,
+            head: vec![0; 1 << Self::HASH_BITS],
+            bits: 0,
+            nbits: 0,
+            obuf: Vec::with_capacity(1024),
+            crc: 0,
+            size: 0,
+        };
+        // magic, deflate, no flags, no mtime, no extra flags, unknown OS
+        s.obuf
+            .extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+        s.put_bits(0b011, 3); // BFINAL, BTYPE=01 (fixed Huffman); the only block
+        AOk(s)
+    }
+    pub fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        self.crc = crc32(self.crc, data);
+        self.size = self.size.wrapping_add(data.len() as u32);
+        while !data.is_empty() {
+            let n = (self.buf.capacity() - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            self.compress(self.buf.len().saturating_sub(Self::MAX_MATCH))?;
+            if self.buf.len() == self.buf.capacity() {
+                self.slide();
+            }
+        }
+        AOk(())
+    }
+    pub fn finish(mut self) -> Result<()> {
+        self.compress(self.buf.len())?;
+        self.put_sym(256)?;
+        if self.nbits > 0 {
+            self.put_bits(0, 8 - self.nbits);
+        }
+        let (crc, size) = (self.crc, self.size);
+        self.obuf.extend_from_slice(&crc.to_le_bytes());
+        self.obuf.extend_from_slice(&size.to_le_bytes());
+        (self.out)(&self.obuf)
+    }
+    fn hash(&self, i: usize) -> usize {
+        let b = &self.buf[i..i + 3];
+        let v = u32::from_le_bytes([b[0], b[1], b[2], 0]);
+        (v.wrapping_mul(2654435761) >> (32 - Self::HASH_BITS)) as usize
+    }
+    fn insert(&mut self, i: usize) {
+        if i + Self::MIN_MATCH <= self.buf.len() {
+            let h = self.hash(i);
+            self.head[h] = i as u16 + 1;
+        }
+    }
+    fn compress(&mut self, limit: usize) -> Result<()> {
+        while self.pos < limit {
+            let pos = self.pos;
+            let mut len = 0;
+            let mut dist = 0;
+            if pos + Self::MIN_MATCH <= self.buf.len() {
+                let cand = self.head[self.hash(pos)] as usize;
+                if cand > 0 && pos - (cand - 1) <= Self::WINDOW {
+                    let cand = cand - 1;
+                    let max = Self::MAX_MATCH.min(self.buf.len() - pos);
+                    len = (0..max)
+                        .take_while(|&k| self.buf[cand + k] == self.buf[pos + k])
+                        .count();
+                    dist = pos - cand;
+                }
+            }
+            if len >= Self::MIN_MATCH {
+                self.put_match(len, dist)?;
+                for i in pos..pos + len {
+                    self.insert(i);
+                }
+                self.pos += len;
+            } else {
+                self.put_sym(u16::from(self.buf[pos]))?;
+                self.insert(pos);
+                self.pos += 1;
+            }
+        }
+        AOk(())
+    }
+    /// Drops input older than the window so more can be buffered.
+    fn slide(&mut self) {
+        let d = self.pos.saturating_sub(Self::WINDOW);
+        self.buf.drain(..d);
+        self.pos -= d;
+        for h in self.head.iter_mut() {
+            *h = if *h as usize > d { *h - d as u16 } else { 0 };
+        }
+    }
+    fn put_bits(&mut self, v: u32, n: u32) {
+        self.bits |= u64::from(v) << self.nbits;
+        self.nbits += n;
+        while self.nbits >= 8 {
+            self.obuf.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+    /// Writes a literal/length symbol with its fixed Huffman code.
+    fn put_sym(&mut self, sym: u16) -> Result<()> {
+        let sym = u32::from(sym);
+        let (code, n) = match sym {
+            0..=143 => (0x30 + sym, 8),
+            144..=255 => (0x190 + sym - 144, 9),
+            256..=279 => (sym - 256, 7),
+            _ => (0xc0 + sym - 280, 8),
+        };
+        self.put_bits(code.reverse_bits() >> (32 - n), n);
+        if self.obuf.len() >= 1024 {
+            (self.out)(&self.obuf)?;
+            self.obuf.clear();
+        }
+        AOk(())
+    }
+    fn put_match(&mut self, len: usize, dist: usize) -> Result<()> {
+        let li = Self::LEN_BASE
+            .iter()
+            .rposition(|&b| b as usize <= len)
+            .unwrap_or(0);
+        let di = Self::DIST_BASE
+            .iter()
+            .rposition(|&b| b as usize <= dist)
+            .unwrap_or(0);
+        self.put_sym(257 + li as u16)?;
+        self.put_bits(
+            (len - Self::LEN_BASE[li] as usize) as u32,
+            Self::LEN_EXTRA[li].into(),
+        );
+        self.put_bits((di as u32).reverse_bits() >> 27, 5);
+        self.put_bits(
+            (dist - Self::DIST_BASE[di] as usize) as u32,
+            Self::DIST_EXTRA[di].into(),
+        );
+        AOk(())
+    }
+}
+pub enum ByteRange {
+    Full,
+    Partial(u64, u64), // inclusive
+    Unsatisfiable,
+}
+/// Parses a single-range `Range: bytes=` header; anything else is served in full.
+pub fn parse_byte_range(h: Option<&str>, len: u64) -> ByteRange {
+    let Some(spec) = h.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((a, b)) = spec.trim().split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Full;
+    };
+    let (a, b) = (a.trim(), b.trim());
+    let (start, end) = match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if b.is_empty() => (start, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if a.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        (Err(_), Ok(_)) if a.is_empty() => return ByteRange::Unsatisfiable,
+        _ => return ByteRange::Full,
+    };
+    if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+pub fn percent_decode(s: &str) -> String {
+    let hex = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < b.len() => match (hex(b[i + 1]), hex(b[i + 2])) {
+                (Some(h), Some(l)) => {
+                    out.push(h << 4 | l);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+pub fn query_param(uri: &str, key: &str) -> Option<String> {
+    let (_, q) = uri.split_once('?')?;
+    q.split('&').find_map(|kv| {
+        let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+        (percent_decode(k) == key).then(|| percent_decode(v))
+    })
+}
+/// Whether an `Accept-Encoding` header allows gzip (not with `q=0`).
+pub fn accepts_gzip(h: Option<&str>) -> bool {
+    h.is_some_and(|h| {
+        h.split(',').any(|e| {
+            let mut p = e.split(';');
+            p.next()
+                .is_some_and(|c| c.trim().eq_ignore_ascii_case("gzip"))
+                && p.all(|q| {
+                    q.trim()
+                        .strip_prefix("q=")
+                        .is_none_or(|q| q.trim().parse::<f32>().is_ok_and(|q| q > 0.0))
+                })
+        })
+    })
+}
+pub(crate) fn cbor_head(buf: &mut Vec<u8>, major: u8, v: u64) {
+    let m = major << 5;
+    match v {
+        0..=23 => buf.push(m | v as u8),
+        24..=0xff => buf.extend_from_slice(&[m | 24, v as u8]),
+        0x100..=0xffff => {
+            buf.push(m | 25);
+            buf.extend_from_slice(&(v as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(m | 26);
+            buf.extend_from_slice(&(v as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(m | 27);
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+}
+pub(crate) fn cbor_str(buf: &mut Vec<u8>, s: &str) {
+    cbor_head(buf, 3, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn unix_time() {
+        let dt = |y, mo, d, h, mi, s| RtcDateTime::new(y, mo, d, h, mi, s);
+        assert_eq!(dt(1970, 1, 1, 0, 0, 0).to_unix(), Some(0));
+        assert_eq!(dt(2000, 2, 29, 12, 0, 0).to_unix(), Some(951_825_600));
+        assert_eq!(dt(2024, 12, 31, 23, 59, 59).to_unix(), Some(1_735_689_599));
+        assert_eq!(dt(2106, 2, 7, 6, 28, 15).to_unix(), Some(u32::MAX));
+        assert_eq!(dt(2106, 2, 7, 6, 28, 16).to_unix(), None);
+        assert_eq!(dt(1969, 12, 31, 23, 59, 59).to_unix(), None);
+        assert_eq!(dt(0, 1, 1, 0, 0, 0).to_unix(), None);
+        assert_eq!(dt(2024, 0, 1, 0, 0, 0).to_unix(), None);
+        assert_eq!(dt(2024, 13, 1, 0, 0, 0).to_unix(), None);
+        assert_eq!(dt(2024, 1, 0, 0, 0, 0).to_unix(), None);
+        assert_eq!(dt(2024, 1, 1, 24, 0, 0).to_unix(), None);
+        assert_eq!(dt(2165, 85, 85, 45, 85, 85).to_unix(), None);
+        for ts in [0, 951_825_600, 1_700_000_000, 4_102_444_799, u32::MAX] {
+            let dt = RtcDateTime::from_unix(ts);
+            assert_eq!(dt.to_unix(), Some(ts));
+            assert_eq!(
+                RtcDateTime::parse(&dt.to_string())
+                    .and_then(|d| d.to_unix())
+                    .is_some(),
+                (2000..=2099).contains(&dt.year)
+            );
+        }
+        assert_eq!(
+            RtcDateTime::from_unix(1_735_689_599).to_string(),
+            "2024-12-31 23:59:59"
+        );
+        assert!(RtcDateTime::parse("2024-12-31T23:59:59").is_some());
+        assert!(RtcDateTime::parse("2024-12-31").is_none());
+        assert!(RtcDateTime::parse("1999-12-31 23:59:59").is_none());
+    }
+    #[test]
+    fn gzip_negotiation() {
+        assert!(accepts_gzip(Some("gzip")));
+        assert!(accepts_gzip(Some("deflate, GZIP;q=0.5, br")));
+        assert!(!accepts_gzip(Some("gzip;q=0")));
+        assert!(!accepts_gzip(Some("deflate, br")));
+        assert!(!accepts_gzip(Some("x-gzip")));
+        assert!(!accepts_gzip(None));
+    }
+    #[test]
+    fn crc32_known_values() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32(0, b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+        assert_eq!(crc32(crc32(0, b"12345"), b"6789"), crc32(0, b"123456789"));
+    }
+    fn range(h: &str, len: u64) -> Option<(u64, u64)> {
+        match parse_byte_range(Some(h), len) {
+            ByteRange::Full => None,
+            ByteRange::Partial(a, b) => Some((a, b)),
+            ByteRange::Unsatisfiable => Some((u64::MAX, u64::MAX)),
+        }
+    }
+    #[test]
+    fn byte_ranges() {
+        const NONE: Option<(u64, u64)> = None;
+        const UNSAT: Option<(u64, u64)> = Some((u64::MAX, u64::MAX));
+        assert!(matches!(parse_byte_range(None, 10), ByteRange::Full));
+        assert_eq!(range("bytes=0-4", 10), Some((0, 4)));
+        assert_eq!(range(" bytes= 2 - 3 ", 10), Some((2, 3)));
+        assert_eq!(range("bytes=5-", 10), Some((5, 9)));
+        assert_eq!(range("bytes=5-100", 10), Some((5, 9)));
+        assert_eq!(range("bytes=-3", 10), Some((7, 9)));
+        assert_eq!(range("bytes=-30", 10), Some((0, 9)));
+        assert_eq!(range("bytes=10-", 10), UNSAT);
+        assert_eq!(range("bytes=-0", 10), UNSAT);
+        assert_eq!(range("bytes=0-", 0), UNSAT);
+        assert_eq!(range("bytes=4-2", 10), NONE);
+        assert_eq!(range("bytes=0-1,3-4", 10), NONE);
+        assert_eq!(range("items=0-4", 10), NONE);
+        assert_eq!(range("bytes=a-b", 10), NONE);
+    }
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(
+            percent_decode("2024-05-01%2012%3A00%3a00"),
+            "2024-05-01 12:00:00"
+        );
+        assert_eq!(percent_decode("a+b"), "a b");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%C3%A9"), "é");
+        assert_eq!(percent_decode("%FF"), "\u{fffd}");
+        assert_eq!(
+            query_param("/get_data?from=2024-05-01+00%3A00%3A00&to=&x", "from").as_deref(),
+            Some("2024-05-01 00:00:00")
+        );
+        assert_eq!(
+            query_param("/get_data?from=1&to=", "to").as_deref(),
+            Some("")
+        );
+        assert_eq!(query_param("/get_data?x", "x").as_deref(), Some(""));
+        assert_eq!(query_param("/get_data?from=1", "to"), None);
+        assert_eq!(query_param("/get_data", "from"), None);
+    }
+    /// Compresses `data` in `chunk`-sized writes and inflates it with flate2.
+    fn round_trip(data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut gz = Vec::new();
+        let mut enc = GzipEncoder::new(|b| {
+            gz.extend_from_slice(b);
+            AOk(())
+        })
+        .unwrap();
+        for c in data.chunks(chunk.max(1)) {
+            enc.write(c).unwrap();
+        }
+        enc.finish().unwrap();
+        let n = gz.len();
+        assert_eq!(gz[n - 8..n - 4], crc32(0, data).to_le_bytes());
+        assert_eq!(gz[n - 4..], (data.len() as u32).to_le_bytes());
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(&gz[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+        gz
+    }
+    #[test]
+    fn gzip_round_trip() {
+        round_trip(b"", 1);
+        round_trip(b"a", 1);
+        round_trip(b"abcabcabcabcabcabc", 1);
+        let csv: Vec<u8> = (0..5000)
+            .flat_map(|i| {
+                format!(
+                    "2024-05-01 12:{:02}:{:02},1.50,12.{:02},0.125,{i}\n",
+                    i / 60 % 60,
+                    i % 60,
+                    i % 100
+                )
+                .into_bytes()
+            })
+            .collect();
+        for chunk in [1, 100, 7 * 1024, csv.len()] {
+            let gz = round_trip(&csv, chunk);
+            assert!(gz.len() < csv.len() / 3);
+        }
+        // incompressible: no matches, all literals
+        let mut x = 1_u32;
+        let noise: Vec<u8> = (0..20_000)
+            .map(|_| {
+                x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (x >> 24) as u8
+            })
+            .collect();
+        round_trip(&noise, 4096);
+        // long runs: overlapping matches at distance 1 and the 258-byte cap
+        round_trip(&[0; 70_000], 10_000);
+    }
+}
@@ -0,0 +1,1572 @@
+use crate::anyhow_lock;
+use crate::codec::cbor_head;
+use crate::codec::cbor_str;
+use crate::codec::crc32;
+use crate::codec::RtcDateTime;
+use crate::storage::Storage;
+use crate::storage::STOR_MIN_FREE;
+use anyhow::Ok as AOk;
+use anyhow::Result;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+// relative to the `Storage` root
+pub const DATA_FILE_PATH: &str = "data.csv";
+pub const DATA_DIR_PATH: &str = "data";
+pub const DATA_ARCHIVE_DIR_PATH: &str = "data_archive";
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetentionPolicy {
+    segment_max_len: u64,
+    max_segments: usize,
+    min_free: usize,
+}
+impl RetentionPolicy {
+    pub const DEFAULT: Self = Self::new(64 * 1024, 256, STOR_MIN_FREE);
+    /// Segments are closed at `segment_max_len` bytes; the oldest are removed
+    /// past `max_segments`, or while less than `min_free` bytes are free.
+    pub const fn new(segment_max_len: u64, max_segments: usize, min_free: usize) -> Self {
+        Self {
+            segment_max_len,
+            max_segments,
+            min_free,
+        }
+    }
+}
+/// One measurement as stored on flash: fixed-size, little-endian,
+/// followed by a CRC-32 of the preceding bytes.
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub seq: u32,    // assigned by DataFile on append
+    pub rtc_ts: u32, // unix seconds, RTC local time
+    pub w: f32,
+    pub v: f32,
+    pub a: f32,
+    pub uptime_ms: u64,
+}
+impl Record {
+    const LEN: usize = 32;
+    /// Records written before `seq` and the CRC were added.
+    const LEGACY_LEN: usize = 24;
+    pub const ZERO: Self = Self {
+        seq: 0,
+        rtc_ts: 0,
+        w: 0.0,
+        v: 0.0,
+        a: 0.0,
+        uptime_ms: 0,
+    };
+    fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut b = [0; Self::LEN];
+        b[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        b[4..8].copy_from_slice(&self.rtc_ts.to_le_bytes());
+        b[8..12].copy_from_slice(&self.w.to_le_bytes());
+        b[12..16].copy_from_slice(&self.v.to_le_bytes());
+        b[16..20].copy_from_slice(&self.a.to_le_bytes());
+        b[20..28].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        let crc = crc32(0, &b[..28]);
+        b[28..32].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+    /// `None` if the CRC doesn't match, i.e. the record is torn or garbage.
+    fn from_bytes(b: &[u8; Self::LEN]) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        if crc32(0, &b[..28]) != u32_at(28) {
+            return None;
+        }
+        Some(Self {
+            seq: u32_at(0),
+            rtc_ts: u32_at(4),
+            w: f32::from_bits(u32_at(8)),
+            v: f32::from_bits(u32_at(12)),
+            a: f32::from_bits(u32_at(16)),
+            uptime_ms: u64::from(u32_at(20)) | (u64::from(u32_at(24)) << 32),
+        })
+    }
+    fn from_legacy_bytes(b: &[u8; Self::LEGACY_LEN]) -> Self {
+        let u32_at = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        Self {
+            seq: 0,
+            rtc_ts: u32_at(0),
+            w: f32::from_bits(u32_at(4)),
+            v: f32::from_bits(u32_at(8)),
+            a: f32::from_bits(u32_at(12)),
+            uptime_ms: u64::from(u32_at(16)) | (u64::from(u32_at(20)) << 32),
+        }
+    }
+    fn parse_csv(l: &str) -> Option<Self> {
+        let mut it = l.split(',');
+        let r = Self {
+            seq: 0,
+            rtc_ts: RtcDateTime::parse(it.next()?)?.to_unix()?,
+            w: it.next()?.parse().ok()?,
+            v: it.next()?.parse().ok()?,
+            a: it.next()?.parse().ok()?,
+            uptime_ms: it.next()?.parse().ok()?,
+        };
+        it.next().is_none().then_some(r)
+    }
+}
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            rtc_ts,
+            w,
+            v,
+            a,
+            uptime_ms,
+            ..
+        } = self;
+        let rtc_ts = RtcDateTime::from_unix(*rtc_ts);
+        write!(f, "{rtc_ts},{w:.2},{v:.2},{a:.3},{uptime_ms}")
+    }
+}
+/// Record layout of a data segment, named by its columns.
+struct Schema {
+    version: u16,
+    columns: &'static str,
+    rec_len: usize,
+    decode: fn(&[u8]) -> Option<Record>,
+}
+/// Every layout this firmware can read, oldest first; the last one is written.
+/// A firmware that changes `Record` adds a version here and keeps a `decode`
+/// for the older ones, so their segments stay readable.
+static SCHEMAS: [Schema; 2] = [
+    Schema {
+        version: 0,
+        columns: DataFile::HEADER,
+        rec_len: Record::LEGACY_LEN,
+        decode: |b| Some(Record::from_legacy_bytes(b.try_into().ok()?)),
+    },
+    Schema {
+        version: 1,
+        columns: DataFile::HEADER,
+        rec_len: Record::LEN,
+        decode: |b| Record::from_bytes(b.try_into().ok()?),
+    },
+];
+impl Schema {
+    /// Segments start with a header of this length: magic, version,
+    /// record length and the NUL-padded column names.
+    const HEADER_LEN: usize = 64;
+    const MAGIC: [u8; 4] = *b"VSEG";
+    fn current() -> &'static Self {
+        &SCHEMAS[SCHEMAS.len() - 1]
+    }
+    /// Segments written before headers existed have none, so their layout is
+    /// told from their first bytes: version 1 if they start with a record that
+    /// passes its CRC, else version 0, which has no CRC.
+    fn headerless(first: &[u8]) -> &'static Self {
+        let crc_ok = first
+            .get(..Record::LEN)
+            .and_then(|b| Record::from_bytes(b.try_into().ok()?))
+            .is_some();
+        if crc_ok {
+            &SCHEMAS[1]
+        } else {
+            &SCHEMAS[0]
+        }
+    }
+    fn header(&self) -> [u8; Self::HEADER_LEN] {
+        let mut b = [0; Self::HEADER_LEN];
+        b[0..4].copy_from_slice(&Self::MAGIC);
+        b[4..6].copy_from_slice(&self.version.to_le_bytes());
+        b[6..8].copy_from_slice(&(self.rec_len as u16).to_le_bytes());
+        b[8..8 + self.columns.len()].copy_from_slice(self.columns.as_bytes());
+        b
+    }
+    /// `None` if the header names a layout this firmware doesn't know.
+    fn from_header(b: &[u8; Self::HEADER_LEN]) -> Option<&'static Self> {
+        let version = u16::from_le_bytes([b[4], b[5]]);
+        let rec_len = u16::from_le_bytes([b[6], b[7]]);
+        let columns = b[8..].split(|&c| c == 0).next().unwrap_or_default();
+        SCHEMAS.iter().find(|s| {
+            s.version == version
+                && s.rec_len == usize::from(rec_len)
+                && s.columns.as_bytes() == columns
+        })
+    }
+}
+/// Calls `op` for every whole record in `f`; `None` for records that fail their CRC.
+fn for_each_record(
+    f: &mut impl Read,
+    schema: &Schema,
+    mut op: impl FnMut(Option<Record>) -> Result<()>,
+) -> Result<()> {
+    let mut buf = vec![0; schema.rec_len * 256];
+    let mut filled = 0;
+    loop {
+        let bytes_read = f.read(&mut buf[filled..])?;
+        if bytes_read == 0 {
+            break;
+        }
+        filled += bytes_read;
+        let whole = filled - filled % schema.rec_len;
+        for c in buf[..whole].chunks_exact(schema.rec_len) {
+            op((schema.decode)(c))?;
+        }
+        buf.copy_within(whole..filled, 0);
+        filled -= whole;
+    }
+    AOk(())
+}
+/// Download formats for the data log. All but CSV carry the device id with
+/// each record and use the column names from `DataFile::HEADER`.
+#[derive(Clone, Copy)]
+pub enum ExportFormat {
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+    /// InfluxDB line protocol, second precision. Timestamps are the RTC's
+    /// local time counted as if it were UTC, like `Record::rtc_ts`; shift them
+    /// on import unless the RTC is set to UTC.
+    Influx,
+    /// An indefinite-length CBOR array of maps; `rtc_ts` is RTC local time too.
+    Cbor,
+}
+/// `x` with `prec` decimals, or JSON `null` for a NaN or infinite reading.
+struct JsonNum(f32, usize);
+impl std::fmt::Display for JsonNum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self(x, prec) = *self;
+        if x.is_finite() {
+            write!(f, "{x:.prec$}")
+        } else {
+            f.write_str("null")
+        }
+    }
+}
+impl ExportFormat {
+    const MEASUREMENT: &str = "vmon";
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "csv" => Some(Self::Csv),
+            "jsonl" => Some(Self::Jsonl),
+            "influx" => Some(Self::Influx),
+            "cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv | Self::Influx => "text/plain",
+            Self::Jsonl => "application/x-ndjson",
+            Self::Cbor => "application/cbor",
+        }
+    }
+    pub fn file_ext(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Jsonl => "jsonl",
+            Self::Influx => "lp",
+            Self::Cbor => "cbor",
+        }
+    }
+    fn begin(self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Self::Csv => writeln!(buf, "{}", DataFile::HEADER)?,
+            Self::Jsonl | Self::Influx => {}
+            Self::Cbor => buf.push(0x9f),
+        }
+        AOk(())
+    }
+    fn write(self, buf: &mut Vec<u8>, r: &Record, dev: &str) -> Result<()> {
+        let Record {
+            rtc_ts,
+            w,
+            v,
+            a,
+            uptime_ms,
+            ..
+        } = *r;
+        match self {
+            Self::Csv => writeln!(buf, "{r}")?,
+            Self::Jsonl => writeln!(
+                buf,
+                "{{\"device\":\"{dev}\",\"rtc_ts\":\"{}\",\"w\":{},\"v\":{},\"a\":{},\"uptime_ms\":{uptime_ms}}}",
+                RtcDateTime::from_unix(rtc_ts),
+                JsonNum(w, 2),
+                JsonNum(v, 2),
+                JsonNum(a, 3)
+            )?,
+            Self::Influx => {
+                // line protocol has no null, so NaN or infinite fields are left out
+                write!(buf, "{},device={dev} ", Self::MEASUREMENT)?;
+                for (k, x, prec) in [("w", w, 2), ("v", v, 2), ("a", a, 3)] {
+                    if x.is_finite() {
+                        write!(buf, "{k}={x:.prec$},")?;
+                    }
+                }
+                writeln!(buf, "uptime_ms={uptime_ms}i {rtc_ts}")?;
+            }
+            Self::Cbor => {
+                cbor_head(buf, 5, 6);
+                cbor_str(buf, "device");
+                cbor_str(buf, dev);
+                cbor_str(buf, "rtc_ts");
+                cbor_head(buf, 6, 1); // epoch-based date/time
+                cbor_head(buf, 0, rtc_ts.into());
+                for (k, x) in [("w", w), ("v", v), ("a", a)] {
+                    cbor_str(buf, k);
+                    buf.push(0xfa);
+                    buf.extend_from_slice(&x.to_be_bytes());
+                }
+                cbor_str(buf, "uptime_ms");
+                cbor_head(buf, 0, uptime_ms);
+            }
+        }
+        AOk(())
+    }
+    fn end(self, buf: &mut Vec<u8>) {
+        if let Self::Cbor = self {
+            buf.push(0xff);
+        }
+    }
+}
+/// (segment id, record number within the segment)
+type RecPos = (u32, u32);
+/// Sparse index entry: the timestamp of record `rec` in segment `seg`.
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    rtc_ts: u32,
+    seg: u32,
+    rec: u32,
+}
+impl IndexEntry {
+    const LEN: usize = 12;
+    fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut b = [0; Self::LEN];
+        b[0..4].copy_from_slice(&self.rtc_ts.to_le_bytes());
+        b[4..8].copy_from_slice(&self.seg.to_le_bytes());
+        b[8..12].copy_from_slice(&self.rec.to_le_bytes());
+        b
+    }
+    fn from_bytes(b: &[u8]) -> Self {
+        let u32_at = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        Self {
+            rtc_ts: u32_at(0),
+            seg: u32_at(4),
+            rec: u32_at(8),
+        }
+    }
+    fn pos(&self) -> RecPos {
+        (self.seg, self.rec)
+    }
+}
+pub struct DataFile<'s> {
+    storage: &'s dyn Storage,
+    dir: &'static str,
+    cur_seg: Option<u32>,
+    next_seq: u32,
+    retention: RetentionPolicy,
+    /// The legacy CSV appended to while there isn't room to migrate it.
+    legacy_csv: Option<String>,
+}
+impl<'s> DataFile<'s> {
+    const LEGACY_PATH: &'static str = DATA_FILE_PATH;
+    const INDEX_NAME: &'static str = "index.bin";
+    /// Holds the data moved aside by the last archiving `clear_data`; removed
+    /// first when retention needs space.
+    const ARCHIVE_DIR: &'static str = DATA_ARCHIVE_DIR_PATH;
+    const INDEX_STRIDE: u32 = 64;
+    const TAIL_SCAN: u64 = 16;
+    const SEG_EXT: &'static str = "bin";
+    const LEGACY_SEG_EXT: &'static str = "csv";
+    /// A segment being migrated from a legacy CSV, renamed to `SEG_EXT` once
+    /// written; `MIGRATE_SRC_NAME` holds the CSV's path meanwhile.
+    const MIGRATING_EXT: &'static str = "mig";
+    const MIGRATE_SRC_NAME: &'static str = "migrate.src";
+    const HEADER: &'static str = "rtc_ts,w,v,a,uptime_ms";
+    pub const fn new(
+        storage: &'s dyn Storage,
+        dir: &'static str,
+        retention: RetentionPolicy,
+    ) -> Self {
+        Self {
+            storage,
+            dir,
+            cur_seg: None,
+            next_seq: 0,
+            retention,
+            legacy_csv: None,
+        }
+    }
+    /// Takes effect from the next append.
+    pub fn set_retention(&mut self, retention: RetentionPolicy) {
+        self.retention = retention;
+    }
+    fn index_path(&self) -> String {
+        format!("{}/{}", self.dir, Self::INDEX_NAME)
+    }
+    fn seg_file(&self, id: u32, ext: &str) -> String {
+        format!("{}/{id:06}.{ext}", self.dir)
+    }
+    fn seg_path(&self, id: u32) -> String {
+        self.seg_file(id, Self::SEG_EXT)
+    }
+    fn parse_seg_name(name: &str, seg_ext: &str) -> Option<u32> {
+        let (id, ext) = name.split_once('.')?;
+        if ext != seg_ext {
+            return None;
+        }
+        id.parse().ok()
+    }
+    fn list_dir(&self, seg_ext: &str) -> Result<Vec<u32>> {
+        let mut ids: Vec<u32> = self
+            .storage
+            .list(self.dir)?
+            .iter()
+            .filter_map(|n| Self::parse_seg_name(n, seg_ext))
+            .collect();
+        ids.sort_unstable();
+        AOk(ids)
+    }
+    fn seg_ids(&self) -> Result<Vec<u32>> {
+        self.list_dir(Self::SEG_EXT)
+    }
+    pub fn init(&mut self) -> Result<()> {
+        self.storage.create_dir_all(self.dir)?;
+        self.finish_migration()?;
+        self.recover_tail()?;
+        self.cur_seg()?;
+        let mut legacy: Vec<String> = self
+            .list_dir(Self::LEGACY_SEG_EXT)?
+            .into_iter()
+            .map(|id| self.seg_file(id, Self::LEGACY_SEG_EXT))
+            .collect();
+        if self.storage.exists(Self::LEGACY_PATH)? {
+            legacy.insert(0, Self::LEGACY_PATH.to_string());
+        }
+        if let Some((path, ..)) = self.read_migration()? {
+            legacy.retain(|p| *p != path);
+            legacy.insert(0, path);
+        }
+        // oldest first, stopping at the first failure to keep them in order
+        self.legacy_csv = None;
+        for path in &legacy {
+            if let Err(e) = self.migrate_csv(path) {
+                log::error!("failed to migrate {path}: {e}");
+                if self.read_migration()?.is_none() {
+                    // not started, so new records can still go after the old
+                    let newest = legacy.last().expect("legacy is not empty");
+                    log::warn!("appending to {newest} until it can be migrated");
+                    self.legacy_csv = Some(newest.clone());
+                }
+                break;
+            }
+        }
+        if !self.storage.exists(&self.index_path())? {
+            self.rebuild_index()?;
+        }
+        AOk(())
+    }
+    /// Returns the schema of segment `id` and the offset of its first record,
+    /// or `None` if the schema is unknown. Empty segments get the current one.
+    fn seg_schema(&self, id: u32) -> Result<Option<(&'static Schema, u64)>> {
+        let current = Some((Schema::current(), Schema::HEADER_LEN as u64));
+        let len = self.seg_len(id)?;
+        if len == 0 {
+            return AOk(current);
+        }
+        let mut b = [0; Schema::HEADER_LEN];
+        let mut f = self.get_seg_read(id);
+        let n = (len as usize).min(Schema::HEADER_LEN);
+        f.read_exact(&mut b[..n])?;
+        if n < Schema::MAGIC.len() || b[..4] != Schema::MAGIC {
+            return AOk(Some((Schema::headerless(&b[..n]), 0)));
+        }
+        if n < Schema::HEADER_LEN {
+            // torn header; `recover_tail` truncates it
+            return AOk(current);
+        }
+        AOk(Schema::from_header(&b).map(|s| (s, Schema::HEADER_LEN as u64)))
+    }
+    /// Number of whole records in segment `id`; 0 if its schema is unknown.
+    fn seg_records(&self, id: u32) -> Result<u64> {
+        let Some((schema, start)) = self.seg_schema(id)? else {
+            return AOk(0);
+        };
+        AOk(self.seg_len(id)?.saturating_sub(start) / schema.rec_len as u64)
+    }
+    fn read_record_at(&self, id: u32, rec: u64) -> Result<Option<Record>> {
+        use std::io::Seek;
+        use std::io::SeekFrom;
+        let Some((schema, start)) = self.seg_schema(id)? else {
+            return AOk(None);
+        };
+        let mut f = self.get_seg_read(id);
+        f.seek(SeekFrom::Start(start + rec * schema.rec_len as u64))?;
+        let mut b = vec![0; schema.rec_len];
+        f.read_exact(&mut b)?;
+        AOk((schema.decode)(&b))
+    }
+    /// Identifies the bytes of a snapshot of `segs`: its oldest record, which
+    /// changes when retention or clearing removes data, and where its newest
+    /// segment ends. `DataSnapshot::truncate_to` reads the end back.
+    fn etag(&self, segs: &[SnapshotSeg]) -> Result<String> {
+        let first = match segs.first() {
+            Some(s) if self.seg_records(s.id)? > 0 => {
+                self.read_record_at(s.id, 0)?.map(|r| (s.id, r.seq))
+            }
+            _ => None,
+        };
+        let (id, seq) = first.unwrap_or_default();
+        let (end, len) = segs.last().map_or((0, 0), |s| (s.id, s.len));
+        AOk(format!("\"{id}-{seq}-{end}-{len}\""))
+    }
+    /// Truncates a torn or corrupt tail left by a brownout mid-write in the
+    /// newest segment, and picks up the sequence number where it left off.
+    fn recover_tail(&mut self) -> Result<()> {
+        use std::io::Seek;
+        use std::io::SeekFrom;
+        let Some(&id) = self.seg_ids()?.last() else {
+            return AOk(());
+        };
+        let Some((schema, start)) = self.seg_schema(id)? else {
+            log::warn!("{}: unknown schema; not recovering", self.seg_path(id));
+            return AOk(());
+        };
+        // only the current layout is appended to; older segments are left as
+        // they are rather than cut at what may just be a different stride
+        let truncate = std::ptr::eq(schema, Schema::current());
+        let rec_len = schema.rec_len as u64;
+        let len = self.seg_len(id)?;
+        let recs = len.saturating_sub(start) / rec_len;
+        let scan_from = start + recs.saturating_sub(Self::TAIL_SCAN) * rec_len;
+        let mut f = self.get_seg_read(id);
+        f.seek(SeekFrom::Start(scan_from))?;
+        let (mut pos, mut good_end, mut last) = (scan_from, scan_from, None);
+        if len < start {
+            good_end = 0;
+        }
+        for_each_record(&mut f, schema, |r| {
+            pos += rec_len;
+            if let Some(r) = r {
+                good_end = pos;
+                last = Some(r);
+            }
+            AOk(())
+        })?;
+        if good_end < len && !truncate {
+            log::warn!(
+                "{}: older segment has {} unreadable bytes at its tail; leaving them",
+                self.seg_path(id),
+                len - good_end
+            );
+        } else if good_end < len {
+            log::warn!(
+                "{}: discarding {} torn/corrupt records ({} bytes) at tail",
+                self.seg_path(id),
+                (len - good_end).div_ceil(rec_len),
+                len - good_end
+            );
+            let f = self
+                .storage
+                .open(&self.seg_path(id), OpenOptions::new().write(true))?;
+            f.set_len(good_end)?;
+            self.storage.sync(&f, 0)?;
+            let mut entries = self.read_index()?;
+            let n = entries.len();
+            entries.retain(|e| e.seg != id || start + u64::from(e.rec) * rec_len < good_end);
+            if entries.len() < n {
+                self.write_index(&entries)?;
+            }
+        }
+        if last.is_none() && good_end > start {
+            last = self.read_record_at(id, (good_end - start) / rec_len - 1)?;
+        }
+        if last.is_none() {
+            // a segment emptied by a torn header; continue from the one before
+            for prev in self.seg_ids()?.into_iter().rev().filter(|&p| p < id) {
+                if let Some(n) = self.seg_records(prev)?.checked_sub(1) {
+                    last = self.read_record_at(prev, n)?;
+                    break;
+                }
+            }
+        }
+        self.next_seq = last.map_or(0, |r| r.seq.wrapping_add(1));
+        AOk(())
+    }
+    fn rebuild_index(&self) -> Result<()> {
+        log::info!("rebuilding {}", self.index_path());
+        let mut entries = Vec::new();
+        for seg in self.seg_ids()? {
+            use std::io::Seek;
+            use std::io::SeekFrom;
+            let Some((schema, start)) = self.seg_schema(seg)? else {
+                continue;
+            };
+            let mut f = self.get_seg_read(seg);
+            f.seek(SeekFrom::Start(start))?;
+            let mut rec: u32 = 0;
+            for_each_record(&mut f, schema, |r| {
+                if let Some(r) = r.filter(|_| rec.is_multiple_of(Self::INDEX_STRIDE)) {
+                    entries.push(IndexEntry {
+                        rtc_ts: r.rtc_ts,
+                        seg,
+                        rec,
+                    });
+                }
+                rec += 1;
+                AOk(())
+            })?;
+            self.storage.feed_watchdog();
+        }
+        self.write_index(&entries)
+    }
+    fn read_index(&self) -> Result<Vec<IndexEntry>> {
+        let b = match self.storage.read(&self.index_path()) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        AOk(b
+            .chunks_exact(IndexEntry::LEN)
+            .map(IndexEntry::from_bytes)
+            .collect())
+    }
+    fn write_index(&self, entries: &[IndexEntry]) -> Result<()> {
+        let b: Vec<u8> = entries.iter().flat_map(|e| e.to_bytes()).collect();
+        self.storage.write(&self.index_path(), &b)?;
+        AOk(())
+    }
+    fn append_index(&self, entries: &[IndexEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return AOk(());
+        }
+        let mut f = self.open_file(
+            &self.index_path(),
+            OpenOptions::new().append(true).create(true),
+        );
+        for e in entries {
+            f.write_all(&e.to_bytes())?;
+        }
+        self.storage
+            .sync(&f, (entries.len() * IndexEntry::LEN) as u64)?;
+        AOk(())
+    }
+    /// Copies the records of legacy CSV `path` into new segments, one
+    /// segment's worth of lines at a time from the end: each segment is
+    /// renamed into place, then its lines are cut off the CSV, so the copy
+    /// never needs more than a segment of free space and an interrupted one
+    /// carries on where it stopped. Fails without writing anything if there
+    /// isn't that much space.
+    fn migrate_csv(&mut self, path: &str) -> Result<()> {
+        let space = self.storage.space_info()?;
+        let need = space.min_allowed_free + self.retention.segment_max_len as usize;
+        if space.free < need {
+            anyhow::bail!("{} bytes free; migrating needs {need}", space.free);
+        }
+        log::info!("migrating {path} to binary segments");
+        let (base, base_seq) = match self.read_migration()? {
+            Some((p, base, seq)) if p == path => (base, seq),
+            _ => {
+                // past both the last segment and the open one, which may not exist yet
+                let last = self.seg_ids()?.last().copied().unwrap_or(0);
+                let cur = self.cur_seg()?;
+                let cur = if self.seg_len(cur)? == 0 {
+                    cur
+                } else {
+                    cur + 1
+                };
+                let base = cur.max(last + 1);
+                let src = format!("{}/{}", self.dir, Self::MIGRATE_SRC_NAME);
+                let marker = format!("{path}\n{base}\n{}", self.next_seq);
+                self.storage.write(&src, marker.as_bytes())?;
+                (base, self.next_seq)
+            }
+        };
+        if self.storage.exists(&self.index_path())? {
+            // rebuilt by `init` with the new segments in it
+            self.storage.remove(&self.index_path())?;
+        }
+        let per_seg =
+            (self.retention.segment_max_len - Schema::HEADER_LEN as u64) / Record::LEN as u64;
+        let starts = self.csv_chunks(path, per_seg)?;
+        let (mut n, mut bad) = (0, 0);
+        for (k, &start) in starts.iter().enumerate().rev() {
+            let id = base + k as u32;
+            // already there if power was lost before the lines were cut
+            if !self.storage.exists(&self.seg_path(id))? {
+                let (recs, b) = self.read_csv_chunk(path, start, per_seg)?;
+                n += recs.len();
+                bad += b;
+                self.cur_seg = Some(id);
+                self.next_seq = base_seq.wrapping_add((k as u64 * per_seg) as u32);
+                if !recs.is_empty() {
+                    self.write_records(Self::MIGRATING_EXT, &recs)?;
+                    self.storage
+                        .rename(&self.seg_file(id, Self::MIGRATING_EXT), &self.seg_path(id))?;
+                }
+            }
+            if k > 0 {
+                let f = self.storage.open(path, OpenOptions::new().write(true))?;
+                f.set_len(start)?;
+                self.storage.sync(&f, 0)?;
+            }
+            self.storage.feed_watchdog();
+        }
+        self.storage.remove(path)?;
+        self.finish_migration()?;
+        self.recover_tail()?;
+        log::info!("migrated {n} records from {path}; skipped {bad} bad lines");
+        AOk(())
+    }
+    /// Byte offsets of the first of each `per_seg` record lines of legacy CSV
+    /// `path`.
+    fn csv_chunks(&self, path: &str, per_seg: u64) -> Result<Vec<u64>> {
+        use std::io::BufRead;
+        let f = self.storage.open(path, OpenOptions::new().read(true))?;
+        let mut r = std::io::BufReader::new(f);
+        let (mut starts, mut pos, mut lines) = (Vec::new(), 0, 0);
+        let mut l = String::new();
+        loop {
+            l.clear();
+            let len = r.read_line(&mut l)? as u64;
+            if len == 0 {
+                break;
+            }
+            if l.trim_end() != Self::HEADER {
+                if lines % per_seg == 0 {
+                    starts.push(pos);
+                }
+                lines += 1;
+            }
+            pos += len;
+        }
+        AOk(starts)
+    }
+    /// Parses up to `per_seg` record lines of legacy CSV `path` from byte
+    /// `start`; also returns how many didn't parse.
+    fn read_csv_chunk(&self, path: &str, start: u64, per_seg: u64) -> Result<(Vec<Record>, usize)> {
+        use std::io::BufRead;
+        use std::io::Seek;
+        use std::io::SeekFrom;
+        let mut f = self.storage.open(path, OpenOptions::new().read(true))?;
+        f.seek(SeekFrom::Start(start))?;
+        let (mut recs, mut bad, mut lines) = (Vec::new(), 0, 0);
+        for l in std::io::BufReader::new(f).lines() {
+            let l = l?;
+            if l == Self::HEADER {
+                continue;
+            }
+            if lines == per_seg {
+                break;
+            }
+            lines += 1;
+            match Record::parse_csv(&l) {
+                Some(r) => recs.push(r),
+                None => bad += 1,
+            }
+        }
+        AOk((recs, bad))
+    }
+    /// The legacy CSV being migrated, the segment its first lines go to and
+    /// their first sequence number.
+    fn read_migration(&self) -> Result<Option<(String, u32, u32)>> {
+        let src = format!("{}/{}", self.dir, Self::MIGRATE_SRC_NAME);
+        let b = match self.storage.read(&src) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return AOk(None),
+            Err(e) => return Err(e.into()),
+        };
+        let marker = String::from_utf8_lossy(&b);
+        let mut lines = marker.lines();
+        let (Some(path), Some(Ok(base)), Some(Ok(seq))) = (
+            lines.next(),
+            lines.next().map(str::parse),
+            lines.next().map(str::parse),
+        ) else {
+            anyhow::bail!("bad migration marker {src}");
+        };
+        AOk(Some((path.to_string(), base, seq)))
+    }
+    /// Drops a segment left half-written by an interrupted migration, which
+    /// `init` then carries on with, or the marker of a finished one.
+    fn finish_migration(&mut self) -> Result<()> {
+        let Some((path, ..)) = self.read_migration()? else {
+            return AOk(());
+        };
+        for id in self.list_dir(Self::MIGRATING_EXT)? {
+            self.storage
+                .remove(&self.seg_file(id, Self::MIGRATING_EXT))?;
+        }
+        if self.storage.exists(&path)? {
+            log::warn!("resuming an interrupted migration of {path}");
+            return AOk(());
+        }
+        self.storage
+            .remove(&format!("{}/{}", self.dir, Self::MIGRATE_SRC_NAME))?;
+        self.cur_seg = None;
+        AOk(())
+    }
+    /// Appends `recs` to the legacy CSV `path` in its own format.
+    fn append_csv(&self, path: &str, recs: &[Record]) -> Result<()> {
+        let mut buf = String::new();
+        if self.storage.file_len(path)? == 0 {
+            buf += &format!("{}\n", Self::HEADER);
+        }
+        for r in recs {
+            buf += &format!("{r}\n");
+        }
+        let mut f = self.storage.append(path)?;
+        f.write_all(buf.as_bytes())?;
+        self.storage.sync(&f, buf.len() as u64)?;
+        AOk(())
+    }
+    fn open_file(&self, path: &str, o: &OpenOptions) -> File {
+        self.storage
+            .open(path, o)
+            .unwrap_or_else(|_| panic!("failed to open file: {path}"))
+    }
+    fn get_seg_append(&self, id: u32, ext: &str) -> File {
+        self.open_file(
+            &self.seg_file(id, ext),
+            OpenOptions::new().append(true).create(true),
+        )
+    }
+    fn get_seg_read(&self, id: u32) -> File {
+        self.open_file(&self.seg_path(id), OpenOptions::new().read(true))
+    }
+    fn seg_len(&self, id: u32) -> Result<u64> {
+        self.storage.file_len(&self.seg_path(id))
+    }
+    fn cur_seg(&mut self) -> Result<u32> {
+        if let Some(id) = self.cur_seg {
+            return AOk(id);
+        }
+        let Some(&last) = self.seg_ids()?.last() else {
+            self.cur_seg = Some(1);
+            return AOk(1);
+        };
+        let current = (Schema::current().version, Schema::HEADER_LEN as u64);
+        if self.seg_schema(last)?.map(|(s, start)| (s.version, start)) == Some(current) {
+            self.cur_seg = Some(last);
+            return AOk(last);
+        }
+        log::info!(
+            "{} has an older or unknown schema; keeping it and starting a new segment",
+            self.seg_path(last)
+        );
+        AOk(self.start_seg(last + 1))
+    }
+    fn start_seg(&mut self, id: u32) -> u32 {
+        log::info!("starting data segment {}", self.seg_path(id));
+        self.cur_seg = Some(id);
+        id
+    }
+    fn rollover_if_needed(&mut self) -> Result<u32> {
+        let id = self.cur_seg()?;
+        if self.seg_len(id)? < self.retention.segment_max_len {
+            return AOk(id);
+        }
+        AOk(self.start_seg(id + 1))
+    }
+    fn remove_seg(&mut self, id: u32) -> Result<()> {
+        log::warn!("retention: removing data segment {}", self.seg_path(id));
+        self.storage.remove(&self.seg_path(id))?;
+        if self.cur_seg == Some(id) {
+            self.cur_seg = None;
+        }
+        AOk(())
+    }
+    fn apply_retention(&mut self) -> Result<()> {
+        if self.storage.space_info()?.free < self.retention.min_free
+            && self.storage.exists(Self::ARCHIVE_DIR)?
+            && SegPin::first(self.storage, Self::ARCHIVE_DIR)?.is_none()
+        {
+            log::warn!("retention: removing data archive");
+            self.storage.remove_dir_all(Self::ARCHIVE_DIR)?;
+        }
+        let mut ids = self.seg_ids()?;
+        let n = ids.len();
+        let pinned = SegPin::first(self.storage, self.dir)?;
+        let removable = |ids: &[u32]| pinned.is_none_or(|p| ids[0] < p);
+        while ids.len() > self.retention.max_segments && removable(&ids) {
+            self.remove_seg(ids.remove(0))?;
+        }
+        while ids.len() > 1
+            && removable(&ids)
+            && self.storage.space_info()?.free < self.retention.min_free
+        {
+            self.remove_seg(ids.remove(0))?;
+        }
+        if ids.len() < n {
+            let first = ids.first().copied().unwrap_or(0);
+            let mut entries = self.read_index()?;
+            entries.retain(|e| e.seg >= first);
+            self.write_index(&entries)?;
+        }
+        AOk(())
+    }
+    fn append_records(&mut self, recs: &[Record]) -> Result<()> {
+        let entries = self.write_records(Self::SEG_EXT, recs)?;
+        self.append_index(&entries)
+    }
+    /// Writes `recs` from the current segment on, to files named with `ext`,
+    /// and returns their index entries.
+    fn write_records(&mut self, ext: &str, recs: &[Record]) -> Result<Vec<IndexEntry>> {
+        if recs.is_empty() {
+            return AOk(Vec::new());
+        }
+        let mut id = self.rollover_if_needed()?;
+        let mut len = self.storage.file_len(&self.seg_file(id, ext))?;
+        let mut f = self.get_seg_append(id, ext);
+        let mut entries = Vec::new();
+        let mut unsynced = 0;
+        for r in recs {
+            let r = Record {
+                seq: self.next_seq,
+                ..*r
+            };
+            self.next_seq = self.next_seq.wrapping_add(1);
+            if len >= self.retention.segment_max_len {
+                self.storage.sync(&f, unsynced)?;
+                unsynced = 0;
+                id = self.start_seg(id + 1);
+                len = 0;
+                f = self.get_seg_append(id, ext);
+            }
+            if len == 0 {
+                f.write_all(&Schema::current().header())?;
+                len = Schema::HEADER_LEN as u64;
+                unsynced += len;
+            }
+            let rec = ((len - Schema::HEADER_LEN as u64) / Record::LEN as u64) as u32;
+            if rec.is_multiple_of(Self::INDEX_STRIDE) {
+                entries.push(IndexEntry {
+                    rtc_ts: r.rtc_ts,
+                    seg: id,
+                    rec,
+                });
+            }
+            f.write_all(&r.to_bytes())?;
+            len += Record::LEN as u64;
+            unsynced += Record::LEN as u64;
+        }
+        self.storage.sync(&f, unsynced)?;
+        AOk(entries)
+    }
+    /// Fails, appending nothing, if retention can't free the minimum space,
+    /// so the caller can keep `recs` and try again later.
+    pub fn append_data(&mut self, recs: &[Record]) -> Result<()> {
+        self.apply_retention()?;
+        if !recs.is_empty() && self.storage.is_free_space_ok().is_err() {
+            anyhow::bail!("append_data canceled due to lack of minimum free space");
+        }
+        if let Some(path) = &self.legacy_csv {
+            return self.append_csv(path, recs);
+        }
+        self.append_records(recs)
+    }
+    /// Captures the current extent of the log, so it can be read after the
+    /// lock is released while appends and retention carry on.
+    pub fn snapshot(&self) -> Result<DataSnapshot<'s>> {
+        let ids = self.seg_ids()?;
+        let pin = SegPin::new(self.storage, self.dir, ids.first().copied().unwrap_or(0))?;
+        let mut segs = Vec::with_capacity(ids.len());
+        for id in ids {
+            let path = self.seg_path(id);
+            let Some((schema, data_start)) = self.seg_schema(id)? else {
+                log::warn!("snapshot: skipping {path} with unknown schema");
+                continue;
+            };
+            let len = self.seg_len(id)?;
+            segs.push(SnapshotSeg {
+                id,
+                path,
+                schema,
+                data_start,
+                len,
+            });
+        }
+        AOk(DataSnapshot {
+            storage: self.storage,
+            index: self.read_index()?,
+            etag: self.etag(&segs)?,
+            segs,
+            _pin: pin,
+        })
+    }
+    /// Read-only view of the data archived by `clear_data`, if any.
+    pub fn archived(&self) -> Result<Option<Self>> {
+        if !self.storage.exists(Self::ARCHIVE_DIR)? {
+            return AOk(None);
+        }
+        AOk(Some(Self::new(
+            self.storage,
+            Self::ARCHIVE_DIR,
+            self.retention,
+        )))
+    }
+    /// With `archive`, the data replaces the archive instead of being deleted.
+    pub fn clear_data(&mut self, archive: bool) -> Result<()> {
+        if SegPin::first(self.storage, self.dir)?.is_some()
+            || archive && SegPin::first(self.storage, Self::ARCHIVE_DIR)?.is_some()
+        {
+            anyhow::bail!("data is being downloaded; try again when it's done");
+        }
+        if archive {
+            if self.storage.exists(Self::ARCHIVE_DIR)? {
+                self.storage.remove_dir_all(Self::ARCHIVE_DIR)?;
+            }
+            self.storage.rename(self.dir, Self::ARCHIVE_DIR)?;
+            self.storage.create_dir_all(self.dir)?;
+        } else {
+            for id in self.seg_ids()? {
+                self.storage.remove(&self.seg_path(id))?;
+            }
+        }
+        if self.legacy_csv.take().is_some() {
+            // the CSVs waiting to be migrated go the same way
+            if !archive {
+                for id in self.list_dir(Self::LEGACY_SEG_EXT)? {
+                    self.storage
+                        .remove(&self.seg_file(id, Self::LEGACY_SEG_EXT))?;
+                }
+            }
+            if self.storage.exists(Self::LEGACY_PATH)? {
+                if archive {
+                    let to = format!("{}/{}", Self::ARCHIVE_DIR, Self::LEGACY_PATH);
+                    self.storage.rename(Self::LEGACY_PATH, &to)?;
+                } else {
+                    self.storage.remove(Self::LEGACY_PATH)?;
+                }
+            }
+        }
+        self.write_index(&[])?;
+        self.cur_seg = None;
+        AOk(())
+    }
+}
+/// Segments in use by snapshots, as (directory path, first segment id) per
+/// snapshot. Retention and clearing leave them, and everything after them, alone.
+struct SegPin {
+    dir: String,
+    first: u32,
+}
+impl SegPin {
+    fn pins() -> Result<MutexGuard<'static, Vec<(String, u32)>>> {
+        static PINS: Mutex<Vec<(String, u32)>> = Mutex::new(Vec::new());
+        anyhow_lock(&PINS, "SegPin pins")
+    }
+    fn new(storage: &dyn Storage, dir: &str, first: u32) -> Result<Self> {
+        let dir = storage.path(dir);
+        Self::pins()?.push((dir.clone(), first));
+        AOk(Self { dir, first })
+    }
+    /// The lowest pinned segment in `dir`, if any.
+    fn first(storage: &dyn Storage, dir: &str) -> Result<Option<u32>> {
+        let dir = storage.path(dir);
+        AOk(Self::pins()?
+            .iter()
+            .filter(|(d, _)| *d == dir)
+            .map(|&(_, first)| first)
+            .min())
+    }
+}
+impl Drop for SegPin {
+    fn drop(&mut self) {
+        if let Ok(mut pins) = Self::pins() {
+            if let Some(i) = pins
+                .iter()
+                .position(|(d, first)| *d == self.dir && *first == self.first)
+            {
+                pins.swap_remove(i);
+            }
+        }
+    }
+}
+struct SnapshotSeg {
+    id: u32,
+    path: String,
+    schema: &'static Schema,
+    data_start: u64,
+    len: u64,
+}
+/// A `DataFile::snapshot`: reads see the log as it was when it was taken.
+pub struct DataSnapshot<'s> {
+    storage: &'s dyn Storage,
+    segs: Vec<SnapshotSeg>,
+    index: Vec<IndexEntry>,
+    pub etag: String,
+    _pin: SegPin,
+}
+impl DataSnapshot<'_> {
+    /// Cuts the snapshot back to the end named by `etag`, an ETag this log
+    /// gave out earlier, so it reads the same bytes as it did then. False if
+    /// data has been removed since or `etag` isn't one of this log's.
+    pub fn truncate_to(&mut self, etag: &str) -> bool {
+        let parse = |e: &str| -> Option<[u64; 4]> {
+            let mut it = e.strip_prefix('"')?.strip_suffix('"')?.split('-');
+            let mut v = [0; 4];
+            for x in &mut v {
+                *x = it.next()?.parse().ok()?;
+            }
+            it.next().is_none().then_some(v)
+        };
+        let (Some(want), Some(cur)) = (parse(etag), parse(&self.etag)) else {
+            return false;
+        };
+        if want == cur {
+            return true;
+        }
+        if want[..2] != cur[..2] {
+            return false;
+        }
+        let (end, len) = (want[2], want[3]);
+        let Some(i) = self.segs.iter().position(|s| u64::from(s.id) == end) else {
+            return false;
+        };
+        if len > self.segs[i].len {
+            return false;
+        }
+        self.segs.truncate(i + 1);
+        self.segs[i].len = len;
+        self.etag = etag.to_string();
+        true
+    }
+    /// Maps an RTC time range onto `[start, end)` record positions using the sparse index.
+    /// Assumes timestamps mostly increase; records are still filtered individually.
+    fn index_bounds(&self, from: Option<u32>, to: Option<u32>) -> (RecPos, Option<RecPos>) {
+        let entries = &self.index;
+        let mut start = (0, 0);
+        if let Some(from) = from {
+            if let Some(e) = entries.iter().rev().find(|e| e.rtc_ts <= from) {
+                start = e.pos();
+            }
+        }
+        let end = to.and_then(|to| {
+            entries
+                .iter()
+                .find(|e| e.pos() > start && e.rtc_ts > to)
+                .map(|e| e.pos())
+        });
+        (start, end)
+    }
+    /// Streams the records with `from <= rtc_ts <= to`, oldest first,
+    /// transcoded to `fmt` and tagged with `dev`.
+    pub fn read_range(
+        &self,
+        from: Option<u32>,
+        to: Option<u32>,
+        fmt: ExportFormat,
+        dev: &str,
+        mut out: impl FnMut(&[u8]) -> Result<()>,
+    ) -> Result<()> {
+        use std::io::Seek;
+        use std::io::SeekFrom;
+        let (start, end) = self.index_bounds(from, to);
+        let in_range = |ts: u32| from.is_none_or(|f| ts >= f) && to.is_none_or(|t| ts <= t);
+        let mut bad = 0;
+        let mut buf = Vec::with_capacity(8 * 1024);
+        fmt.begin(&mut buf)?;
+        for seg in &self.segs {
+            let id = seg.id;
+            if id < start.0 || end.is_some_and(|e| id > e.0) {
+                continue;
+            }
+            let rec_len = seg.schema.rec_len as u64;
+            let recs = seg.len.saturating_sub(seg.data_start) / rec_len;
+            let first = if id == start.0 { u64::from(start.1) } else { 0 };
+            let limit = match end {
+                Some((e, rec)) if e == id => u64::from(rec).min(recs),
+                _ => recs,
+            };
+            if first >= limit {
+                continue;
+            }
+            let mut f = self
+                .storage
+                .open(&seg.path, OpenOptions::new().read(true))?;
+            f.seek(SeekFrom::Start(seg.data_start + first * rec_len))?;
+            for_each_record(&mut f.take((limit - first) * rec_len), seg.schema, |r| {
+                match r {
+                    Some(r) if in_range(r.rtc_ts) => fmt.write(&mut buf, &r, dev)?,
+                    Some(_) => {}
+                    None => bad += 1,
+                }
+                if buf.len() >= 7 * 1024 {
+                    out(&buf)?;
+                    buf.clear();
+                }
+                AOk(())
+            })?;
+        }
+        if bad > 0 {
+            log::warn!("read_range skipped {bad} records with bad CRC");
+        }
+        fmt.end(&mut buf);
+        out(&buf)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::DirStorage;
+
+    const T0: u32 = 1_700_000_000;
+    fn rec(i: u32) -> Record {
+        Record {
+            rtc_ts: T0 + i * 10,
+            w: 1.5,
+            v: 12.5,
+            a: 0.125,
+            uptime_ms: u64::from(i) * 10_000,
+            ..Record::ZERO
+        }
+    }
+    fn recs(r: std::ops::Range<u32>) -> Vec<Record> {
+        r.map(rec).collect()
+    }
+    /// Room for `per_seg` records in each segment.
+    fn policy(per_seg: u64, max_segments: usize, min_free: usize) -> RetentionPolicy {
+        RetentionPolicy::new(
+            Schema::HEADER_LEN as u64 + per_seg * Record::LEN as u64,
+            max_segments,
+            min_free,
+        )
+    }
+    /// The `rtc_ts` of each record `read_range` returns.
+    fn read_ts(f: &DataFile, from: Option<u32>, to: Option<u32>) -> Result<Vec<u32>> {
+        let mut csv = Vec::new();
+        f.snapshot()?
+            .read_range(from, to, ExportFormat::Csv, "test", |b| {
+                csv.extend_from_slice(b);
+                AOk(())
+            })?;
+        let csv = String::from_utf8(csv)?;
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(DataFile::HEADER));
+        lines
+            .map(|l| Record::parse_csv(l).map(|r| r.rtc_ts))
+            .collect::<Option<_>>()
+            .ok_or(anyhow::anyhow!("bad CSV line in {csv}"))
+    }
+    fn ts(r: std::ops::Range<u32>) -> Vec<u32> {
+        r.map(|i| rec(i).rtc_ts).collect()
+    }
+
+    #[test]
+    fn retention_keeps_newest_segments() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(10, 3, 0));
+        f.init()?;
+        for i in (0..100).step_by(5) {
+            f.append_data(&recs(i..i + 5))?;
+        }
+        f.append_data(&[])?;
+        assert_eq!(f.seg_ids()?.len(), 3);
+        assert_eq!(read_ts(&f, None, None)?, ts(70..100));
+        let first = f.seg_ids()?[0];
+        assert!(f.read_index()?.iter().all(|e| e.seg >= first));
+        Ok(())
+    }
+    #[test]
+    fn retention_frees_space() -> Result<()> {
+        let seg_len = policy(10, 0, 0).segment_max_len as usize;
+        let st = DirStorage::temp(6 * seg_len, 3 * seg_len)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(10, 256, 3 * seg_len));
+        f.init()?;
+        for i in (0..100).step_by(10) {
+            f.append_data(&recs(i..i + 10))?;
+        }
+        f.append_data(&[])?;
+        assert!(st.space_info()?.free >= 3 * seg_len);
+        let kept = read_ts(&f, None, None)?;
+        assert!(!kept.is_empty() && kept.len() < 100);
+        assert_eq!(kept, ts(100 - kept.len() as u32..100));
+        Ok(())
+    }
+    #[test]
+    fn append_fails_when_space_cant_be_freed() -> Result<()> {
+        let st = DirStorage::temp(4000, 1000)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(100, 256, 1000));
+        f.init()?;
+        f.append_data(&recs(0..80))?;
+        f.append_data(&recs(80..100))?;
+        // the newest segment is always kept, and it alone leaves too little room
+        assert!(f.append_data(&recs(100..105)).is_err());
+        assert_eq!(read_ts(&f, None, None)?, ts(0..100));
+        f.append_data(&[])?;
+        Ok(())
+    }
+    #[test]
+    fn snapshot_pins_segments_against_retention() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(10, 2, 0));
+        f.init()?;
+        f.append_data(&recs(0..20))?;
+        let snap = f.snapshot()?;
+        f.append_data(&recs(20..40))?;
+        f.append_data(&recs(40..60))?;
+        let mut n = 0;
+        snap.read_range(None, None, ExportFormat::Jsonl, "test", |b| {
+            n += b.iter().filter(|&&c| c == b'\n').count();
+            AOk(())
+        })?;
+        assert_eq!(n, 20);
+        assert!(f.clear_data(false).is_err());
+        drop(snap);
+        f.append_data(&[])?;
+        assert_eq!(read_ts(&f, None, None)?, ts(40..60));
+        Ok(())
+    }
+    #[test]
+    fn recover_tail_truncates_torn_records() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, RetentionPolicy::DEFAULT);
+        f.init()?;
+        f.append_data(&recs(0..20))?;
+        let id = f.cur_seg()?;
+        let good_len = f.seg_len(id)?;
+        let mut torn = rec(20).to_bytes();
+        torn[9] ^= 0xff;
+        let mut s = st.append(&f.seg_path(id))?;
+        s.write_all(&torn)?;
+        s.write_all(&rec(21).to_bytes()[..10])?;
+        drop(s);
+
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, RetentionPolicy::DEFAULT);
+        f.init()?;
+        assert_eq!(f.seg_len(id)?, good_len);
+        f.append_data(&recs(20..22))?;
+        assert_eq!(f.read_record_at(id, 20)?.map(|r| r.seq), Some(20));
+        assert_eq!(read_ts(&f, None, None)?, ts(0..22));
+        Ok(())
+    }
+    #[test]
+    fn recover_tail_truncates_torn_header() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(10, 256, 0));
+        f.init()?;
+        f.append_data(&recs(0..10))?;
+        st.write(&f.seg_path(2), &Schema::current().header()[..20])?;
+
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(10, 256, 0));
+        f.init()?;
+        assert_eq!(f.seg_len(2)?, 0);
+        f.append_data(&recs(10..15))?;
+        assert_eq!(f.read_record_at(2, 0)?.map(|r| r.seq), Some(10));
+        assert_eq!(read_ts(&f, None, None)?, ts(0..15));
+        Ok(())
+    }
+    fn legacy_bytes(r: &Record) -> Vec<u8> {
+        let mut b = r.rtc_ts.to_le_bytes().to_vec();
+        for x in [r.w, r.v, r.a] {
+            b.extend_from_slice(&x.to_le_bytes());
+        }
+        b.extend_from_slice(&r.uptime_ms.to_le_bytes());
+        b
+    }
+    #[test]
+    fn headerless_segments_keep_their_layout() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, RetentionPolicy::DEFAULT);
+        f.init()?;
+        let mut v0: Vec<u8> = recs(0..5).iter().flat_map(legacy_bytes).collect();
+        v0.extend_from_slice(&[0xaa; 10]);
+        let v1: Vec<u8> = recs(5..10).iter().flat_map(|r| r.to_bytes()).collect();
+        st.write(&f.seg_path(1), &v0)?;
+        st.write(&f.seg_path(2), &v1)?;
+        st.remove(&f.index_path())?;
+
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, RetentionPolicy::DEFAULT);
+        f.init()?;
+        assert_eq!(f.seg_len(1)?, v0.len() as u64);
+        assert_eq!(f.seg_schema(1)?.map(|(s, _)| s.version), Some(0));
+        assert_eq!(f.seg_schema(2)?.map(|(s, _)| s.version), Some(1));
+        f.append_data(&recs(10..12))?;
+        assert_eq!(f.seg_ids()?, [1, 2, 3]);
+        assert_eq!(read_ts(&f, None, None)?, ts(0..12));
+        Ok(())
+    }
+    #[test]
+    fn etag_names_the_snapshot_end() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(10, 2, 0));
+        f.init()?;
+        f.append_data(&recs(0..15))?;
+        let old = f.snapshot()?.etag;
+        let csv = |snap: &DataSnapshot| -> Result<Vec<u8>> {
+            let mut b = Vec::new();
+            snap.read_range(None, None, ExportFormat::Csv, "test", |c| {
+                b.extend_from_slice(c);
+                AOk(())
+            })?;
+            AOk(b)
+        };
+        let old_csv = csv(&f.snapshot()?)?;
+        f.append_data(&recs(15..18))?;
+        let mut snap = f.snapshot()?;
+        assert_ne!(snap.etag, old);
+        assert!(snap.truncate_to(&old));
+        assert_eq!(snap.etag, old);
+        assert_eq!(csv(&snap)?, old_csv);
+        assert!(!snap.truncate_to("\"1-0-9-99999\""));
+        assert!(!snap.truncate_to("W/\"x\""));
+        // once unpinned, retention drops the first segment and old prefixes are gone
+        drop(snap);
+        f.append_data(&recs(18..30))?;
+        f.append_data(&[])?;
+        assert!(!f.snapshot()?.truncate_to(&old));
+        Ok(())
+    }
+    fn export(fmt: ExportFormat, r: &Record) -> Result<String> {
+        let mut buf = Vec::new();
+        fmt.write(&mut buf, r, "dev1")?;
+        AOk(String::from_utf8(buf)?)
+    }
+    #[test]
+    fn exports_skip_non_finite_readings() -> Result<()> {
+        let r = Record {
+            w: f32::NAN,
+            a: f32::INFINITY,
+            ..rec(0)
+        };
+        let j: serde_json::Value = serde_json::from_str(&export(ExportFormat::Jsonl, &r)?)?;
+        assert!(j["w"].is_null() && j["a"].is_null());
+        assert_eq!(j["v"], 12.5);
+        assert_eq!(j["rtc_ts"], "2023-11-14 22:13:20");
+        assert_eq!(
+            export(ExportFormat::Influx, &r)?,
+            format!("vmon,device=dev1 v=12.50,uptime_ms=0i {T0}\n")
+        );
+        assert_eq!(
+            export(ExportFormat::Influx, &rec(1))?,
+            format!(
+                "vmon,device=dev1 w=1.50,v=12.50,a=0.125,uptime_ms=10000i {}\n",
+                T0 + 10
+            )
+        );
+        Ok(())
+    }
+    fn legacy_csv(r: std::ops::Range<u32>) -> String {
+        let mut csv = format!("{}\n", DataFile::HEADER);
+        for r in recs(r) {
+            csv += &format!("{r}\n");
+        }
+        csv
+    }
+    #[test]
+    fn migrates_legacy_csv() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let csv = legacy_csv(0..100) + "not,a,record\n";
+        st.write(DATA_FILE_PATH, csv.as_bytes())?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(40, 256, 0));
+        f.init()?;
+        assert!(!st.exists(DATA_FILE_PATH)?);
+        assert_eq!(f.seg_ids()?, [1, 2, 3]);
+        assert!(f.list_dir(DataFile::MIGRATING_EXT)?.is_empty());
+        assert_eq!(read_ts(&f, None, None)?, ts(0..100));
+        assert_eq!(f.read_index()?.len(), 3);
+        Ok(())
+    }
+    #[test]
+    fn interrupted_migration_resumes() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        st.write(DATA_FILE_PATH, legacy_csv(0..100).as_bytes())?;
+        std::fs::create_dir_all(st.path(DATA_DIR_PATH))?;
+        st.write(
+            "data/migrate.src",
+            format!("{DATA_FILE_PATH}\n1\n0").as_bytes(),
+        )?;
+        // power lost after the last segment was renamed into place, before
+        // its lines were cut off the CSV, while writing the one before it
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(40, 256, 0));
+        f.cur_seg = Some(3);
+        f.next_seq = 80;
+        f.write_records(DataFile::SEG_EXT, &recs(80..100))?;
+        f.cur_seg = Some(2);
+        f.write_records(DataFile::MIGRATING_EXT, &recs(40..50))?;
+
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(40, 256, 0));
+        f.init()?;
+        assert!(!st.exists(DATA_FILE_PATH)?);
+        assert!(!st.exists("data/migrate.src")?);
+        assert_eq!(f.seg_ids()?, [1, 2, 3]);
+        assert_eq!(read_ts(&f, None, None)?, ts(0..100));
+        assert_eq!(f.read_record_at(2, 0)?.map(|r| r.seq), Some(40));
+        assert_eq!(f.next_seq, 100);
+        Ok(())
+    }
+    #[test]
+    fn migration_waits_for_space() -> Result<()> {
+        let csv = legacy_csv(0..100);
+        let min_free = 2000;
+        let seg = policy(40, 256, 0).segment_max_len as usize;
+        // room for a segment, not for the whole copy
+        let st = DirStorage::temp(csv.len() + min_free + seg - 1, min_free)?;
+        st.write(DATA_FILE_PATH, csv.as_bytes())?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(40, 256, 0));
+        f.init()?;
+        assert_eq!(st.read(DATA_FILE_PATH)?, csv.as_bytes());
+        assert!(f.seg_ids()?.is_empty());
+        assert!(!st.exists("data/migrate.src")?);
+        // kept in the CSV meanwhile
+        f.append_data(&recs(100..105))?;
+        assert_eq!(st.read(DATA_FILE_PATH)?, legacy_csv(0..105).as_bytes());
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(40, 256, 0));
+        f.init()?;
+        assert_eq!(st.read(DATA_FILE_PATH)?, legacy_csv(0..105).as_bytes());
+
+        let csv = legacy_csv(0..105);
+        let st = DirStorage::new(st.root(), csv.len() + min_free + seg, min_free)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(40, 256, 0));
+        f.init()?;
+        assert!(!st.exists(DATA_FILE_PATH)?);
+        assert_eq!(f.seg_ids()?, [1, 2, 3]);
+        assert_eq!(read_ts(&f, None, None)?, ts(0..105));
+        f.append_data(&recs(105..106))?;
+        assert_eq!(read_ts(&f, None, None)?, ts(0..106));
+        Ok(())
+    }
+    #[test]
+    fn index_rebuild_matches_appended() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(100, 256, 0));
+        f.init()?;
+        for i in (0..300).step_by(30) {
+            f.append_data(&recs(i..i + 30))?;
+        }
+        let appended: Vec<_> = f
+            .read_index()?
+            .iter()
+            .map(|e| (e.pos(), e.rtc_ts))
+            .collect();
+        let strides = 100_u32.div_ceil(DataFile::INDEX_STRIDE);
+        assert_eq!(appended.len() as u32, 3 * strides);
+        assert_eq!(appended[1], ((1, DataFile::INDEX_STRIDE), rec(64).rtc_ts));
+
+        st.remove(&f.index_path())?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(100, 256, 0));
+        f.init()?;
+        let rebuilt: Vec<_> = f
+            .read_index()?
+            .iter()
+            .map(|e| (e.pos(), e.rtc_ts))
+            .collect();
+        assert_eq!(rebuilt, appended);
+        Ok(())
+    }
+    #[test]
+    fn index_bounds_select_range() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = DataFile::new(&st, DATA_DIR_PATH, policy(100, 256, 0));
+        f.init()?;
+        f.append_data(&recs(0..300))?;
+        let (from, to) = (rec(150).rtc_ts, rec(210).rtc_ts);
+        let snap = f.snapshot()?;
+        assert_eq!(
+            snap.index_bounds(Some(from), Some(to)),
+            ((2, 0), Some((3, 64)))
+        );
+        assert_eq!(read_ts(&f, Some(from), Some(to))?, ts(150..211));
+        assert_eq!(read_ts(&f, Some(from + 5), None)?, ts(151..300));
+        assert_eq!(read_ts(&f, None, Some(rec(0).rtc_ts))?, ts(0..1));
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+//! The parts of the firmware that don't touch ESP-IDF: the data log and its
+//! export formats, settings, and the storage they sit on. Kept separate so
+//! they build and are tested on the host with `cargo test-host`.
+pub mod codec;
+pub mod data;
+pub mod settings;
+pub mod storage;
+
+use anyhow::Result;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+pub fn anyhow_lock<'a, T>(v: &'a Mutex<T>, err_prefix: &'static str) -> Result<MutexGuard<'a, T>> {
+    v.lock()
+        .map_err(|e| anyhow::anyhow!("{err_prefix} anyhow_lock error: {e}"))
+}
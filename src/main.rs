@@ -6,20 +6,32 @@ use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::fs::littlefs::Littlefs;
 use esp_idf_svc::hal::delay::TickType;
 use esp_idf_svc::hal::delay::TickType_t;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::gpio::Gpio9;
+use esp_idf_svc::hal::gpio::Input;
 use esp_idf_svc::hal::gpio::OutputPin;
+use esp_idf_svc::hal::gpio::PinDriver;
+use esp_idf_svc::hal::gpio::Pull;
 use esp_idf_svc::hal::i2c::I2cConfig;
 use esp_idf_svc::hal::i2c::I2cDriver;
+use esp_idf_svc::hal::i2c::I2C0;
 use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::hal::peripheral::Peripheral;
 use esp_idf_svc::hal::peripherals::Peripherals;
 use esp_idf_svc::hal::rmt::RmtChannel;
 use esp_idf_svc::hal::units::FromValueType;
 use esp_idf_svc::http::server::Configuration as HttpConf;
+use esp_idf_svc::http::server::EspHttpConnection;
 use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::server::Request;
 use esp_idf_svc::io::vfs::MountedLittlefs;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::nvs::EspNvs;
+use esp_idf_svc::nvs::NvsDefault;
 use esp_idf_svc::sys::esp_deep_sleep;
+use esp_idf_svc::sys::esp_efuse_mac_get_default;
 use esp_idf_svc::sys::esp_littlefs_info;
+use esp_idf_svc::sys::esp_random;
 use esp_idf_svc::sys::esp_restart;
 use esp_idf_svc::sys::esp_sleep_get_wakeup_cause;
 use esp_idf_svc::sys::esp_timer_get_time;
@@ -35,9 +47,6 @@ use std::cell::OnceCell;
 use std::ffi::CStr;
 use std::fs;
 use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Read;
-use std::io::Write;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
@@ -48,27 +57,43 @@ use std::sync::MutexGuard;
 use std::thread::sleep;
 use std::time::Duration;
 use std::time::Instant;
+use vmon::anyhow_lock;
+use vmon::codec::accepts_gzip;
+use vmon::codec::parse_byte_range;
+use vmon::codec::query_param;
+use vmon::codec::ByteRange;
+use vmon::codec::GzipEncoder;
+use vmon::codec::RtcDateTime;
+use vmon::data::DataFile;
+use vmon::data::ExportFormat;
+use vmon::data::Record;
+use vmon::data::RetentionPolicy;
+use vmon::data::DATA_DIR_PATH;
+use vmon::settings::BoardSettings;
+use vmon::settings::DataSettings;
+use vmon::settings::Ina219Settings;
+use vmon::settings::PowerSettings;
+use vmon::settings::Settings;
+use vmon::settings::SettingsBundle;
+use vmon::settings::SettingsErrors;
+use vmon::settings::SettingsFile;
+use vmon::settings::SettingsMirror;
+use vmon::settings::SettingsSource;
+use vmon::storage::CsvLog;
+use vmon::storage::Storage;
+use vmon::storage::StorageSpaceInfo;
+use vmon::storage::STOR_MIN_FREE;
 use ws2812_esp32_rmt_driver::Ws2812Esp32RmtDriver;
 
-#[derive(Serialize, Deserialize)]
-struct Settings {
-    wifi_pass: String,
-    wifi_ssid: String,
-}
-impl Default for Settings {
-    fn default() -> Self {
-        Self {
-            wifi_pass: "kspass1234".to_string(),
-            wifi_ssid: "ESP2".to_string(),
-        }
-    }
-}
-
 const STOR_LBL_CSTR: &CStr = c"storage";
 const STOR_LBL_STR: &str = "storage";
 const STOR_PATH: &str = "/storage";
-const DATA_FILE_PATH: &str = "/storage/data.csv";
-const SETTINGS_FILE_PATH: &str = "/storage/settings.json";
+// relative to the `Storage` root
+const ROLLUP_HOUR_PATH: &str = "rollup_hour.csv";
+const ROLLUP_DAY_PATH: &str = "rollup_day.csv";
+const ROLLUP_STATE_PATH: &str = "rollups.json";
+const ROLLUP_STATE_TMP_PATH: &str = "rollups.json.tmp";
+const EVENTS_FILE_PATH: &str = "events.csv";
 fn try_mount_storage(fmt: bool) -> Result<MountedLittlefs<Littlefs<()>>> {
     let mut littlefs: Littlefs<()> = unsafe { Littlefs::new_partition(STOR_LBL_STR) }?;
     if fmt {
@@ -78,41 +103,236 @@ fn try_mount_storage(fmt: bool) -> Result<MountedLittlefs<Littlefs<()>>> {
     AOk(mounted)
 }
 fn mount_storage() -> Result<MountedLittlefs<Littlefs<()>>> {
-    match try_mount_storage(false) {
-        Ok(mounted) => AOk(mounted),
+    let mounted = match try_mount_storage(false) {
+        Ok(mounted) => mounted,
         Err(e) => {
             log::info!("mount failed: {e}; formatting");
-            try_mount_storage(true)
+            STORAGE_HEALTH.note_format(&e.to_string());
+            let mounted = try_mount_storage(true)?;
+            Events::log("storage_formatted", &e.to_string());
+            mounted
+        }
+    };
+    STORAGE_HEALTH.note(|c| c.mounts += 1);
+    AOk(mounted)
+}
+/// The littlefs partition mounted by `mount_storage`.
+struct LittlefsStorage;
+impl Storage for LittlefsStorage {
+    fn root(&self) -> &str {
+        STOR_PATH
+    }
+    fn write(&self, rel: &str, b: &[u8]) -> std::io::Result<()> {
+        fs::write(self.path(rel), b)?;
+        STORAGE_HEALTH.note(|c| {
+            c.writes += 1;
+            c.bytes_written += b.len() as u64;
+        });
+        std::io::Result::Ok(())
+    }
+    fn sync(&self, f: &File, bytes: u64) -> std::io::Result<()> {
+        f.sync_all()?;
+        STORAGE_HEALTH.note(|c| {
+            c.writes += 1;
+            c.syncs += 1;
+            c.bytes_written += bytes;
+        });
+        std::io::Result::Ok(())
+    }
+    fn space_info(&self) -> Result<StorageSpaceInfo> {
+        let mut total = 0;
+        let mut used = 0;
+        let res = unsafe { esp_littlefs_info(STOR_LBL_CSTR.as_ptr(), &mut total, &mut used) };
+        if res != 0 {
+            anyhow::bail!("esp_littlefs_info failed; esp_err_t = {res}");
         }
+        AOk(StorageSpaceInfo {
+            total,
+            free: total - used,
+            min_allowed_free: STOR_MIN_FREE,
+        })
+    }
+    fn feed_watchdog(&self) {
+        feed_watchdog();
     }
 }
-#[derive(Serialize, Deserialize)]
-struct StorageSpaceInfo {
-    total: usize,
-    free: usize,
-    min_allowed_free: usize,
-}
-const STOR_MIN_FREE: usize = 512 * 1024;
-fn get_storage_space_info() -> Result<StorageSpaceInfo> {
-    let mut total = 0;
-    let mut used = 0;
-    let res = unsafe { esp_littlefs_info(STOR_LBL_CSTR.as_ptr(), &mut total, &mut used) };
-    if res != 0 {
-        anyhow::bail!("esp_littlefs_info failed; esp_err_t = {res}");
-    }
-    AOk(StorageSpaceInfo {
-        total,
-        free: total - used,
-        min_allowed_free: STOR_MIN_FREE,
-    })
-}
-fn is_free_space_ok() -> Result<()> {
-    let i = get_storage_space_info()?;
-    if i.free < i.min_allowed_free {
-        anyhow::bail!("not enough free space")
-    } else {
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct StorageCounters {
+    mounts: u32,
+    formats: u32,
+    writes: u32,
+    syncs: u32,
+    bytes_written: u64,
+}
+impl StorageCounters {
+    const ZERO: Self = Self {
+        mounts: 0,
+        formats: 0,
+        writes: 0,
+        syncs: 0,
+        bytes_written: 0,
+    };
+    fn add(&mut self, o: &Self) {
+        self.mounts += o.mounts;
+        self.formats += o.formats;
+        self.writes += o.writes;
+        self.syncs += o.syncs;
+        self.bytes_written += o.bytes_written;
+    }
+}
+/// Littlefs activity since boot and over the device's lifetime. Counts since
+/// the last NVS update are kept in RTC memory and persisted in batches, so
+/// low-power wakeups don't wear NVS either.
+struct StorageHealth {
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+impl StorageHealth {
+    const NAMESPACE: &str = "stor_health";
+    const LAST_FORMAT_KEY: &str = "last_fmt_err";
+    const PERSIST_WRITES: u32 = 64;
+    /// littlefs block size on the ESP32-C3 (one flash sector).
+    const BLOCK_SIZE: usize = 4096;
+    const fn new() -> Self {
+        Self { nvs: None }
+    }
+    /// `op` gets the counters since boot and those not yet persisted.
+    fn with_rtc<T>(
+        &mut self,
+        op: impl FnOnce(&mut StorageCounters, &mut StorageCounters) -> T,
+    ) -> T {
+        #[link_section = ".rtc.data"]
+        static mut SINCE_BOOT: StorageCounters = StorageCounters::ZERO;
+        #[link_section = ".rtc.data"]
+        static mut PENDING: StorageCounters = StorageCounters::ZERO;
+        unsafe {
+            let (since_boot, pending) = (&raw mut SINCE_BOOT, &raw mut PENDING);
+            op(&mut *since_boot, &mut *pending)
+        }
+    }
+    fn init(&mut self, part: EspDefaultNvsPartition) -> Result<()> {
+        self.nvs = Some(EspNvs::new(part, Self::NAMESPACE, true)?);
         AOk(())
     }
+    fn read_nvs(&self) -> Result<StorageCounters> {
+        let Some(nvs) = &self.nvs else {
+            return AOk(StorageCounters::ZERO);
+        };
+        AOk(StorageCounters {
+            mounts: nvs.get_u32("mounts")?.unwrap_or(0),
+            formats: nvs.get_u32("formats")?.unwrap_or(0),
+            writes: nvs.get_u32("writes")?.unwrap_or(0),
+            syncs: nvs.get_u32("syncs")?.unwrap_or(0),
+            bytes_written: nvs.get_u64("bytes_written")?.unwrap_or(0),
+        })
+    }
+    fn persist(&mut self) -> Result<()> {
+        let pending = self.with_rtc(|_, pending| *pending);
+        if self.nvs.is_none() || pending == StorageCounters::ZERO {
+            return AOk(());
+        }
+        let mut c = self.read_nvs()?;
+        c.add(&pending);
+        let nvs = self.nvs.as_mut().expect("checked above");
+        nvs.set_u32("mounts", c.mounts)?;
+        nvs.set_u32("formats", c.formats)?;
+        nvs.set_u32("writes", c.writes)?;
+        nvs.set_u32("syncs", c.syncs)?;
+        nvs.set_u64("bytes_written", c.bytes_written)?;
+        self.with_rtc(|_, pending| *pending = StorageCounters::ZERO);
+        AOk(())
+    }
+    fn note(&mut self, op: impl Fn(&mut StorageCounters)) -> Result<()> {
+        let n = self.with_rtc(|since_boot, pending| {
+            op(since_boot);
+            op(pending);
+            pending.writes
+        });
+        if n >= Self::PERSIST_WRITES {
+            self.persist()?;
+        }
+        AOk(())
+    }
+    fn note_format(&mut self, reason: &str) -> Result<()> {
+        self.note(|c| c.formats += 1)?;
+        if let Some(nvs) = &mut self.nvs {
+            nvs.set_str(Self::LAST_FORMAT_KEY, reason)?;
+        }
+        self.persist()
+    }
+    fn last_format_reason(&self) -> Result<Option<String>> {
+        let Some(nvs) = &self.nvs else {
+            return AOk(None);
+        };
+        let mut buf = [0; 256];
+        AOk(nvs
+            .get_str(Self::LAST_FORMAT_KEY, &mut buf)?
+            .map(str::to_string))
+    }
+    fn report(&mut self, space: StorageSpaceInfo) -> Result<StorageHealthReport> {
+        let (since_boot, pending) = self.with_rtc(|since_boot, pending| (*since_boot, *pending));
+        let mut lifetime = self.read_nvs()?;
+        lifetime.add(&pending);
+        let block_count = space.total / Self::BLOCK_SIZE;
+        // Assume each sync rewrites about one block (copy-on-write tail and
+        // metadata commit) on top of the data itself.
+        let est_block_erases =
+            lifetime.bytes_written / Self::BLOCK_SIZE as u64 + u64::from(lifetime.syncs);
+        AOk(StorageHealthReport {
+            block_size: Self::BLOCK_SIZE,
+            block_count,
+            blocks_used: (space.total - space.free).div_ceil(Self::BLOCK_SIZE),
+            since_boot,
+            lifetime,
+            est_block_erases,
+            est_erase_cycles_per_block: est_block_erases as f64 / block_count.max(1) as f64,
+            last_format_reason: self.last_format_reason()?,
+        })
+    }
+}
+#[derive(Serialize)]
+struct StorageHealthReport {
+    block_size: usize,
+    block_count: usize,
+    blocks_used: usize,
+    since_boot: StorageCounters,
+    lifetime: StorageCounters,
+    est_block_erases: u64,
+    est_erase_cycles_per_block: f64,
+    last_format_reason: Option<String>,
+}
+struct LockedStorageHealth {
+    locker: Mutex<StorageHealth>,
+}
+impl LockedStorageHealth {
+    const fn new() -> Self {
+        Self {
+            locker: Mutex::new(StorageHealth::new()),
+        }
+    }
+    fn lock(&self) -> Result<MutexGuard<'_, StorageHealth>> {
+        anyhow_lock(&self.locker, "LockedStorageHealth lock")
+    }
+    fn init(&self, part: EspDefaultNvsPartition) -> Result<()> {
+        self.lock().and_then(|mut h| h.init(part))
+    }
+    fn persist(&self) -> Result<()> {
+        self.lock().and_then(|mut h| h.persist())
+    }
+    /// Failures are only logged so that counting never fails a storage operation.
+    fn note(&self, op: impl Fn(&mut StorageCounters)) {
+        if let Err(e) = self.lock().and_then(|mut h| h.note(op)) {
+            log::error!("storage health update failed: {e}");
+        }
+    }
+    fn note_format(&self, reason: &str) {
+        if let Err(e) = self.lock().and_then(|mut h| h.note_format(reason)) {
+            log::error!("storage health update failed: {e}");
+        }
+    }
+    fn report(&self) -> Result<StorageHealthReport> {
+        let space = STORAGE.space_info()?;
+        self.lock().and_then(|mut h| h.report(space))
+    }
 }
 
 struct LastLine {
@@ -134,151 +354,166 @@ impl LastLine {
         AOk(())
     }
 }
-struct DataFile {}
-impl DataFile {
-    const PATH: &str = DATA_FILE_PATH;
-    const HEADER: &str = "rtc_ts,w,v,a,uptime_ms";
-    const fn new() -> Self {
-        Self {}
-    }
-    fn open_file(&self, o: &mut OpenOptions) -> File {
-        o.open(Self::PATH)
-            .unwrap_or_else(|_| panic!("failed to open file: {}", Self::PATH))
-    }
-    fn get_file_append(&self) -> File {
-        self.open_file(OpenOptions::new().append(true).create(true))
-    }
-    fn get_file_read(&self) -> File {
-        self.get_file_append(); // to create if it doesn't exist
-        self.open_file(OpenOptions::new().read(true))
-    }
-    fn len(&self) -> Result<u64> {
-        let f = self.get_file_read();
-        let len = f.metadata()?.len();
-        AOk(len)
-    }
-    fn append_line_raw(&self, l: &str) -> Result<()> {
-        let mut f = self.get_file_append();
-        writeln!(f, "{l}")?;
-        f.sync_all()?;
-        AOk(())
-    }
-    fn write_header_if_needed(&self) -> Result<()> {
-        if self.len()? == 0 {
-            self.append_line_raw(Self::HEADER)?;
-        }
-        AOk(())
-    }
-    fn append_data(&self, d: &str) -> Result<()> {
-        if is_free_space_ok().is_err() {
-            log::warn!("append_data canceled due to lack of minimum free space");
-            return AOk(());
-        }
-        self.write_header_if_needed()?;
-        self.append_line_raw(d)?;
-        LAST_LINE.set(d)?;
-        AOk(())
-    }
-    fn clear_data(&self) -> Result<()> {
-        fs::remove_file(Self::PATH)?;
-        AOk(())
-    }
+/// Identifies this unit in exported data; derived from the factory MAC.
+fn device_id() -> &'static str {
+    static ID: LazyLock<String> = LazyLock::new(|| {
+        let mut mac = [0u8; 6];
+        unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+        let hex: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+        format!("vmon-{hex}")
+    });
+    &ID
 }
 struct LockedDataFile {
-    locker: LazyLock<Mutex<DataFile>>,
+    locker: LazyLock<Mutex<DataFile<'static>>>,
 }
 impl LockedDataFile {
     const fn new() -> Self {
         Self {
-            locker: LazyLock::new(|| Mutex::new(DataFile::new())),
+            locker: LazyLock::new(|| {
+                Mutex::new(DataFile::new(
+                    &STORAGE,
+                    DATA_DIR_PATH,
+                    RetentionPolicy::DEFAULT,
+                ))
+            }),
         }
     }
-    fn lock(&self) -> Result<MutexGuard<'_, DataFile>> {
+    fn lock(&self) -> Result<MutexGuard<'_, DataFile<'static>>> {
         anyhow_lock(&self.locker, "LockedDataFile lock")
     }
-    fn append_data(&self, d: &str) -> Result<()> {
-        self.lock().and_then(|f| f.append_data(d))
+    fn init(&self, retention: RetentionPolicy) -> Result<()> {
+        self.lock().and_then(|mut f| {
+            f.set_retention(retention);
+            f.init()
+        })
     }
-    fn clear_data(&self) -> Result<()> {
-        self.lock().and_then(|f| f.clear_data())
+    fn append_data(&self, recs: &[Record]) -> Result<()> {
+        self.lock().and_then(|mut f| f.append_data(recs))
+    }
+    fn clear_data(&self, archive: bool) -> Result<()> {
+        self.lock().and_then(|mut f| f.clear_data(archive))
     }
 }
-struct SettingsFile {}
-impl SettingsFile {
-    const PATH: &str = SETTINGS_FILE_PATH;
-    const fn new() -> Self {
-        Self {}
+/// The NVS copy of the settings, which survives littlefs being reformatted.
+struct NvsSettingsMirror {
+    nvs: EspNvs<NvsDefault>,
+}
+impl NvsSettingsMirror {
+    const NAMESPACE: &str = "settings";
+    const NVS_KEY: &str = "json";
+    fn new(part: EspDefaultNvsPartition) -> Result<Self> {
+        AOk(Self {
+            nvs: EspNvs::new(part, Self::NAMESPACE, true)?,
+        })
     }
-    fn set_str(&self, s: &str) -> Result<()> {
-        fs::write(Self::PATH, s)?;
+}
+impl SettingsMirror for NvsSettingsMirror {
+    fn get(&self) -> Result<Option<String>> {
+        let Some(len) = self.nvs.str_len(Self::NVS_KEY)? else {
+            return AOk(None);
+        };
+        let mut buf = vec![0u8; len];
+        AOk(self
+            .nvs
+            .get_str(Self::NVS_KEY, &mut buf)?
+            .map(str::to_string))
+    }
+    fn set(&mut self, s: &str) -> Result<()> {
+        self.nvs.set_str(Self::NVS_KEY, s)?;
         AOk(())
     }
-    fn set_default_if_needed(&self, force: bool) -> Result<()> {
-        if !fs::exists(Self::PATH)? || force {
-            let s = serde_json::to_string(&Settings::default())?;
-            self.set_str(&s)?;
+}
+struct LockedSettingsFile {
+    locker: LazyLock<Mutex<SettingsFile<'static>>>,
+}
+impl LockedSettingsFile {
+    const fn new() -> Self {
+        Self {
+            locker: LazyLock::new(|| Mutex::new(SettingsFile::new(&STORAGE))),
         }
-        AOk(())
     }
-    fn set(&self, s: &Settings) -> Result<()> {
-        let s = serde_json::to_string(s)?;
-        self.set_str(&s)?;
-        AOk(())
+    fn lock(&self) -> Result<MutexGuard<'_, SettingsFile<'static>>> {
+        anyhow_lock(&self.locker, "LockedSettingsFile lock")
     }
-    fn get_str_from_utf8(&self) -> Result<String> {
-        self.set_default_if_needed(false)?;
-        let r = fs::read(Self::PATH)?;
-        let s = String::from_utf8(r)?;
-        AOk(s)
+    fn init(&self, part: EspDefaultNvsPartition) -> Result<()> {
+        let mirror = NvsSettingsMirror::new(part)?;
+        self.lock().map(|mut f| f.set_mirror(Box::new(mirror)))
     }
-    fn get_str(&self) -> Result<String> {
-        let s = match self.get_str_from_utf8() {
-            Ok(s) => s,
-            Err(e) => {
-                log::error!("{} is bad; error: {}; resetting to defaults", Self::PATH, e);
-                self.set_default_if_needed(true)?;
-                self.get_str_from_utf8()?
-            }
-        };
-        AOk(s)
+    fn set(&self, s: &Settings) -> Result<()> {
+        self.lock().and_then(|mut f| f.set(s))
     }
     fn get(&self) -> Result<Settings> {
-        let str = self.get_str()?;
-        let s = match serde_json::from_str(&str) {
-            Ok(s) => s,
-            Err(e) => {
-                log::error!(
-                    "'{}' from {} is bad; error: {}; resetting to defaults",
-                    str,
-                    Self::PATH,
-                    e
-                );
-                self.set_default_if_needed(true)?;
-                serde_json::from_str(&self.get_str()?)?
-            }
-        };
+        let (s, from) = self.lock().and_then(|mut f| f.load())?;
+        if from == SettingsSource::Mirror {
+            Events::log("settings", "restored from NVS");
+        }
         AOk(s)
     }
 }
-struct LockedSettingsFile {
-    locker: LazyLock<Mutex<SettingsFile>>,
+/// Single-use token that must accompany a destructive request, so a browser
+/// prefetch or stray click can't trigger it on its own.
+struct ConfirmToken {
+    cur: Mutex<Option<(u32, Instant)>>,
 }
-impl LockedSettingsFile {
+impl ConfirmToken {
+    const TTL: Duration = Duration::from_secs(60);
     const fn new() -> Self {
         Self {
-            locker: LazyLock::new(|| Mutex::new(SettingsFile::new())),
+            cur: Mutex::new(None),
         }
     }
-    fn lock(&self) -> Result<MutexGuard<'_, SettingsFile>> {
-        anyhow_lock(&self.locker, "LockedSettingsFile lock")
+    fn issue(&self) -> Result<String> {
+        let t = unsafe { esp_random() };
+        *anyhow_lock(&self.cur, "ConfirmToken issue")? = Some((t, Instant::now()));
+        AOk(format!("{t:08x}"))
     }
-    fn set(&self, s: &Settings) -> Result<()> {
-        self.lock().and_then(|f| f.set(s))
+    /// Consumes the pending token; true if `t` matched it and it hadn't expired.
+    fn redeem(&self, t: &str) -> Result<bool> {
+        let cur = anyhow_lock(&self.cur, "ConfirmToken redeem")?.take();
+        AOk(cur.is_some_and(|(cur, issued)| {
+            issued.elapsed() < Self::TTL && u32::from_str_radix(t, 16).is_ok_and(|t| t == cur)
+        }))
     }
-    fn get(&self) -> Result<Settings> {
-        self.lock().and_then(|f| f.get())
+}
+/// Lengths of recent `/get_data` responses, keyed by ETag and URI, so only
+/// the first download of a given log extent transcodes it twice.
+struct DownloadTotals {
+    recent: Mutex<Vec<(String, u64)>>,
+}
+impl DownloadTotals {
+    const LEN: usize = 4;
+    const fn new() -> Self {
+        Self {
+            recent: Mutex::new(Vec::new()),
+        }
+    }
+    fn get(&self, key: &str) -> Result<Option<u64>> {
+        let recent = anyhow_lock(&self.recent, "DownloadTotals get")?;
+        AOk(recent.iter().find(|(k, _)| k == key).map(|&(_, n)| n))
+    }
+    /// The ETag of the most recent response for `uri`.
+    fn latest_etag(&self, uri: &str) -> Result<Option<String>> {
+        let recent = anyhow_lock(&self.recent, "DownloadTotals latest_etag")?;
+        AOk(recent.iter().rev().find_map(|(k, _)| {
+            let (etag, u) = k.split_once(' ')?;
+            (u == uri).then(|| etag.to_string())
+        }))
+    }
+    fn insert(&self, key: String, total: u64) -> Result<()> {
+        let mut recent = anyhow_lock(&self.recent, "DownloadTotals insert")?;
+        recent.retain(|(k, _)| *k != key);
+        if recent.len() == Self::LEN {
+            recent.remove(0);
+        }
+        recent.push((key, total));
+        AOk(())
     }
 }
+static CLEAR_DATA_TOKEN: ConfirmToken = ConfirmToken::new();
+static DOWNLOAD_TOTALS: DownloadTotals = DownloadTotals::new();
+static STORAGE: LittlefsStorage = LittlefsStorage;
+static STORAGE_HEALTH: LockedStorageHealth = LockedStorageHealth::new();
 static LAST_LINE: LastLine = LastLine::new();
 static DATA_FILE: LockedDataFile = LockedDataFile::new();
 static SETTINGS_FILE: LockedSettingsFile = LockedSettingsFile::new();
@@ -301,6 +536,12 @@ fn feed_watchdog() {
     }
 }
 fn restart() {
+    if let Err(e) = RtcRecords::flush() {
+        log::error!("flush before restart failed: {e}");
+    }
+    if let Err(e) = STORAGE_HEALTH.persist() {
+        log::error!("storage health persist before restart failed: {e}");
+    }
     unsafe {
         esp_restart();
     }
@@ -329,27 +570,360 @@ fn get_smoothed<const I: usize>(val: f64) -> f64 {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct RtcDateTime {
-    year: u16,
-    month: u8,
-    day: u8,
-    hour: u8,
-    minute: u8,
-    second: u8,
-}
-impl RtcDateTime {
-    fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+/// Records buffered in RTC memory so low-power wakeups don't touch flash every time.
+/// Survives deep sleep but not a power-on or software reset.
+struct RtcRecords;
+impl RtcRecords {
+    const CAP: usize = 32;
+    const FLUSH_LEN: usize = 16;
+    fn with<T>(op: impl FnOnce(&mut [Record; Self::CAP], &mut usize) -> T) -> T {
+        #[link_section = ".rtc.data"]
+        static mut RTC_RECORDS: [Record; RtcRecords::CAP] = [Record::ZERO; RtcRecords::CAP];
+        #[link_section = ".rtc.data"]
+        static mut RTC_RECORDS_LEN: usize = 0;
+        unsafe {
+            let (buf, len) = (&raw mut RTC_RECORDS, &raw mut RTC_RECORDS_LEN);
+            if *len > Self::CAP {
+                *len = 0;
+            }
+            op(&mut *buf, &mut *len)
+        }
+    }
+    /// Returns the number of buffered records.
+    fn push(r: Record) -> usize {
+        Self::with(|buf, len| {
+            if *len == Self::CAP {
+                log::warn!("RTC record buffer full; dropping oldest record");
+                buf.copy_within(1.., 0);
+                *len -= 1;
+            }
+            buf[*len] = r;
+            *len += 1;
+            *len
+        })
+    }
+    fn flush() -> Result<()> {
+        let recs = Self::with(|buf, len| buf[..*len].to_vec());
+        if recs.is_empty() {
+            return AOk(());
+        }
+        DATA_FILE.append_data(&recs)?;
+        log::info!("flushed {} buffered records", recs.len());
+        Self::with(|_, len| *len = 0);
+        if let Err(e) = Rollups::persist() {
+            log::error!("failed to save rollups: {e}");
+        }
+        AOk(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum RollupPeriod {
+    Hour,
+    Day,
+}
+impl RollupPeriod {
+    const ALL: [Self; 2] = [Self::Hour, Self::Day];
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "hour" => Some(Self::Hour),
+            "day" => Some(Self::Day),
+            _ => None,
+        }
+    }
+    fn secs(self) -> u32 {
+        match self {
+            Self::Hour => 3600,
+            Self::Day => 86400,
+        }
+    }
+    fn log(self) -> &'static CsvLog<'static> {
+        static HOUR: CsvLog<'static> =
+            CsvLog::new(&STORAGE, ROLLUP_HOUR_PATH, Rollup::HEADER, 64 * 1024);
+        static DAY: CsvLog<'static> =
+            CsvLog::new(&STORAGE, ROLLUP_DAY_PATH, Rollup::HEADER, 64 * 1024);
+        match self {
+            Self::Hour => &HOUR,
+            Self::Day => &DAY,
+        }
+    }
+}
+/// Aggregate of the records within one hour or day.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct Rollup {
+    start: u32,
+    n: u32,
+    v_min: f32,
+    v_max: f32,
+    v_sum: f64,
+    a_peak: f32,
+    wh: f64,
+    ah: f64,
+}
+impl Rollup {
+    const HEADER: &str = "period_start,n,v_min,v_max,v_mean,a_peak,wh,ah";
+    const fn new(start: u32) -> Self {
         Self {
-            year,
-            month,
-            day,
-            hour,
-            minute,
-            second,
+            start,
+            n: 0,
+            v_min: f32::MAX,
+            v_max: f32::MIN,
+            v_sum: 0.0,
+            a_peak: f32::MIN,
+            wh: 0.0,
+            ah: 0.0,
+        }
+    }
+    fn add(&mut self, r: &Record, dt: u32) {
+        self.n += 1;
+        self.v_min = self.v_min.min(r.v);
+        self.v_max = self.v_max.max(r.v);
+        self.v_sum += f64::from(r.v);
+        self.a_peak = self.a_peak.max(r.a);
+        self.wh += f64::from(r.w) * f64::from(dt) / 3600.0;
+        self.ah += f64::from(r.a) * f64::from(dt) / 3600.0;
+    }
+}
+impl std::fmt::Display for Rollup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            start,
+            n,
+            v_min,
+            v_max,
+            v_sum,
+            a_peak,
+            wh,
+            ah,
+        } = self;
+        let start = RtcDateTime::from_unix(*start);
+        let v_mean = v_sum / f64::from((*n).max(1));
+        write!(
+            f,
+            "{start},{n},{v_min:.2},{v_max:.2},{v_mean:.2},{a_peak:.3},{wh:.3},{ah:.4}"
+        )
+    }
+}
+/// Hourly and daily rollups in progress, kept in RTC memory across deep sleep;
+/// finished periods are appended to their `CsvLog`. The in-progress state is
+/// also saved to `ROLLUP_STATE_PATH` with each finished period and, at most
+/// every `PERSIST_SECS`, a data flush, and restored from it after a power loss
+/// clears RTC memory.
+struct Rollups;
+#[derive(Serialize, Deserialize)]
+struct RollupState {
+    cur: [Rollup; 2],
+    last_ts: u32,
+}
+impl Rollups {
+    /// Gaps longer than this (e.g. the device was off) aren't integrated into Wh/Ah.
+    const MAX_GAP_SECS: u32 = 300;
+    /// How much record time a power loss may cost the in-progress rollups;
+    /// saving on every flush would double the flash writes in high-power mode.
+    const PERSIST_SECS: u32 = 15 * 60;
+    /// `op` gets the rollups in progress, the last record's time and that of
+    /// the last save.
+    fn with<T>(op: impl FnOnce(&mut [Rollup; 2], &mut u32, &mut u32) -> T) -> Result<T> {
+        static LOCK: Mutex<()> = Mutex::new(());
+        #[link_section = ".rtc.data"]
+        static mut RTC_ROLLUPS: [Rollup; 2] = [Rollup::new(0); 2];
+        #[link_section = ".rtc.data"]
+        static mut RTC_ROLLUPS_LAST_TS: u32 = 0;
+        #[link_section = ".rtc.data"]
+        static mut RTC_ROLLUPS_SAVED_TS: u32 = 0;
+        let _l = anyhow_lock(&LOCK, "Rollups with")?;
+        unsafe {
+            let (rollups, last_ts, saved_ts) = (
+                &raw mut RTC_ROLLUPS,
+                &raw mut RTC_ROLLUPS_LAST_TS,
+                &raw mut RTC_ROLLUPS_SAVED_TS,
+            );
+            let (rollups, last_ts, saved_ts) = (&mut *rollups, &mut *last_ts, &mut *saved_ts);
+            if *last_ts == 0 {
+                match Self::load() {
+                    Ok(Some(st)) => {
+                        (*rollups, *last_ts, *saved_ts) = (st.cur, st.last_ts, st.last_ts)
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::error!("failed to restore rollups: {e}"),
+                }
+            }
+            AOk(op(rollups, last_ts, saved_ts))
+        }
+    }
+    fn load() -> Result<Option<RollupState>> {
+        match STORAGE.read(ROLLUP_STATE_PATH) {
+            Ok(b) => AOk(Some(serde_json::from_slice(&b)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => AOk(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn save(cur: &[Rollup; 2], last_ts: u32, saved_ts: &mut u32) -> Result<()> {
+        let b = serde_json::to_vec(&RollupState { cur: *cur, last_ts })?;
+        STORAGE.write(ROLLUP_STATE_TMP_PATH, &b)?;
+        STORAGE.rename(ROLLUP_STATE_TMP_PATH, ROLLUP_STATE_PATH)?;
+        *saved_ts = last_ts;
+        AOk(())
+    }
+    fn add(r: &Record) -> Result<()> {
+        Self::with(|rollups, last_ts, saved_ts| {
+            let dt = r.rtc_ts.saturating_sub(*last_ts);
+            let dt = if dt <= Self::MAX_GAP_SECS { dt } else { 0 };
+            *last_ts = r.rtc_ts;
+            let mut finished = false;
+            for (p, cur) in RollupPeriod::ALL.into_iter().zip(rollups.iter_mut()) {
+                let start = r.rtc_ts - r.rtc_ts % p.secs();
+                if cur.start != start {
+                    if cur.n > 0 {
+                        p.log().append(&cur.to_string())?;
+                        finished = true;
+                    }
+                    *cur = Rollup::new(start);
+                }
+                cur.add(r, dt);
+            }
+            if finished {
+                // so a restore can't append the finished periods again
+                Self::save(rollups, *last_ts, saved_ts)?;
+            }
+            AOk(())
+        })?
+    }
+    /// Saves the in-progress state if `PERSIST_SECS` of records have gone by
+    /// since the last save (or the clock went back); called once the records
+    /// it covers are in the data file.
+    fn persist() -> Result<()> {
+        Self::with(|rollups, last_ts, saved_ts| {
+            if (*saved_ts..saved_ts.saturating_add(Self::PERSIST_SECS)).contains(last_ts) {
+                return AOk(());
+            }
+            Self::save(rollups, *last_ts, saved_ts)
+        })?
+    }
+    /// Streams the finished periods followed by the one still in progress.
+    /// Only the in-progress one is read under the lock.
+    fn read(p: RollupPeriod, mut out: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        let cur = Self::with(|rollups, _, _| rollups[p as usize])?;
+        p.log().read_all(&mut out)?;
+        // if it finished meanwhile, the log may already hold it
+        let still_cur = Self::with(|rollups, _, _| rollups[p as usize].start == cur.start)?;
+        if cur.n > 0 && still_cur {
+            out(format!("{cur}\n").as_bytes())?;
+        }
+        AOk(())
+    }
+}
+
+/// Last RTC reading and when it was taken, so events can be timestamped
+/// without the I2C bus.
+static RTC_SYNC: Mutex<Option<(u32, Instant)>> = Mutex::new(None);
+fn rtc_now() -> Option<u32> {
+    let sync = *RTC_SYNC.lock().ok()?;
+    sync.map(|(ts, at)| ts + at.elapsed().as_secs() as u32)
+}
+/// Persistent log of boots, mode changes, errors, settings changes and
+/// restarts, for units in the field where nobody watches the serial output.
+struct Events;
+impl Events {
+    const HEADER: &str = "rtc_ts,uptime_ms,event,detail";
+    fn log_file() -> &'static CsvLog<'static> {
+        static LOG: CsvLog<'static> =
+            CsvLog::new(&STORAGE, EVENTS_FILE_PATH, Events::HEADER, 32 * 1024);
+        &LOG
+    }
+    /// Failing to persist the event is only logged.
+    fn log(event: &str, detail: &str) {
+        log::info!("event {event}: {detail}");
+        let ts = rtc_now()
+            .map(|ts| RtcDateTime::from_unix(ts).to_string())
+            .unwrap_or_default();
+        let detail = detail.replace(['\r', '\n'], " ");
+        let detail = if detail.contains([',', '"']) {
+            format!("\"{}\"", detail.replace('"', "\"\""))
+        } else {
+            detail
+        };
+        let line = format!("{ts},{},{event},{detail}", uptime_usec() / 1000);
+        if let Err(e) = Self::log_file().append(&line) {
+            log::error!("failed to log event {event}: {e}");
+        }
+    }
+    fn read(out: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        Self::log_file().read_all(out)
+    }
+}
+#[derive(Clone, Copy, PartialEq)]
+enum PowerMode {
+    High,
+    Low,
+    VeryLow,
+}
+impl PowerMode {
+    /// Logs an event when the mode differs from the one before the last
+    /// deep sleep, so routine low-power wakeups don't fill the event log.
+    fn note(self, why: &str) {
+        #[link_section = ".rtc.data"]
+        static mut LAST_MODE: Option<PowerMode> = None;
+        let last_mode = &raw mut LAST_MODE;
+        let last = unsafe { *last_mode };
+        if last == Some(self) {
+            return;
+        }
+        let from = last.map_or("boot", Self::name);
+        let wake = if woke_from_sleep() {
+            format!("; wake={}", wake_cause())
+        } else {
+            String::new()
+        };
+        Events::log("mode", &format!("{from} -> {}: {why}{wake}", self.name()));
+        unsafe { *last_mode = Some(self) };
+    }
+    fn name(self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Low => "low",
+            Self::VeryLow => "very_low",
         }
     }
 }
+/// Logs measurement failures once per failing streak rather than every wakeup.
+fn note_measurement_result(r: &Result<f64>) {
+    #[link_section = ".rtc.data"]
+    static mut FAILING: bool = false;
+    let failing = &raw mut FAILING;
+    match (r, unsafe { *failing }) {
+        (Err(e), false) => Events::log("measure_error", &e.to_string()),
+        (Ok(_), true) => Events::log("measure_ok", "measurements recovered"),
+        _ => {}
+    }
+    unsafe { *failing = r.is_err() };
+}
+fn wake_cause() -> &'static str {
+    use esp_idf_svc::sys;
+    match unsafe { sys::esp_sleep_get_wakeup_cause() } {
+        sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => "none",
+        sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => "timer",
+        sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_GPIO => "gpio",
+        _ => "other",
+    }
+}
+fn reset_reason() -> &'static str {
+    use esp_idf_svc::sys;
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        sys::esp_reset_reason_t_ESP_RST_EXT => "external",
+        sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT | sys::esp_reset_reason_t_ESP_RST_TASK_WDT => {
+            "task_watchdog"
+        }
+        sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        _ => "unknown",
+    }
+}
+
 struct DS3231 {
     addr: u8,
     timeout: TickType_t,
@@ -395,17 +969,7 @@ impl DS3231 {
         AOk(RtcDateTime::new(year, month, day, hour, minute, second))
     }
     fn read_rtc_str(&mut self, i2c: &mut I2cDriver) -> Result<String> {
-        let RtcDateTime {
-            year,
-            month,
-            day,
-            hour,
-            minute,
-            second,
-        } = self.read_rtc(i2c)?;
-        AOk(format!(
-            "{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}"
-        ))
+        self.read_rtc(i2c).map(|dt| dt.to_string())
     }
 }
 struct INA219 {
@@ -425,11 +989,10 @@ impl INA219 {
     const REG_POWER_W: u8 = 0x03;
     const REG_CURRENT_A: u8 = 0x04;
     const REG_CALIBRATE: u8 = 0x05;
-    const INTERNAL_FIXED_VALUE: f64 = 0.04096;
     const SHUNT_VOLTAGE_LSB: f64 = 0.000010; // 10 μV
     const BUS_VOLTAGE_LSB: f64 = 0.004; // 4 mV
     fn new(addr: u8, r_shunt: f64, max_expected_current: f64, conf: u16) -> Self {
-        let current_lsb = max_expected_current / 2_f64.powi(15);
+        let current_lsb = Ina219Settings::current_lsb(max_expected_current);
         Self {
             addr,
             timeout: TickType::new_millis(100).0,
@@ -437,7 +1000,7 @@ impl INA219 {
             _max_expected_current: max_expected_current,
             current_lsb,
             power_lsb: 20_f64 * current_lsb,
-            calibration: (Self::INTERNAL_FIXED_VALUE / (current_lsb * r_shunt)) as u16,
+            calibration: Ina219Settings::calibration(r_shunt, max_expected_current) as u16,
             conf,
         }
     }
@@ -489,8 +1052,44 @@ struct I2cDevices {
     ds3231: DS3231,
     ina219: INA219,
 }
+impl From<&Ina219Settings> for INA219 {
+    fn from(s: &Ina219Settings) -> Self {
+        Self::new(s.addr, s.shunt_ohms, s.max_current_a, s.conf)
+    }
+}
 impl I2cDevices {
-    fn new(i2c: I2cDriver<'static>, ds3231: DS3231, ina219: INA219) -> Result<Self> {
+    /// `free` holds the `BoardSettings::I2C_PINS`, in order; the board's SDA
+    /// and SCL are taken from it once the devices are up, so a failed attempt
+    /// leaves them, and `i2c`, for another.
+    fn new(
+        i2c: &mut I2C0,
+        free: &mut [Option<AnyIOPin>],
+        board: &BoardSettings,
+        mut ds3231: DS3231,
+        mut ina219: INA219,
+    ) -> Result<Self> {
+        let slot = |n: u8| {
+            BoardSettings::I2C_PINS
+                .iter()
+                .position(|&p| p == n)
+                .filter(|&i| free[i].is_some())
+                .ok_or(anyhow::anyhow!("GPIO {n} isn't available for I2C"))
+        };
+        let (sda, scl) = (slot(board.i2c_sda)?, slot(board.i2c_scl)?);
+        // SAFETY: a failed attempt's driver is dropped before the next one is
+        // made; after success the pins are dropped and `i2c` is never used again
+        let (i2c, sda_pin, scl_pin) = unsafe {
+            (
+                i2c.clone_unchecked(),
+                free[sda].as_mut().expect("slot checked").clone_unchecked(),
+                free[scl].as_mut().expect("slot checked").clone_unchecked(),
+            )
+        };
+        let config = I2cConfig::new().baudrate(board.i2c_hz.Hz());
+        let i2c = I2cDriver::new(i2c, sda_pin, scl_pin, &config)?;
+        let timeout = TickType::new_millis(board.i2c_timeout_ms.into()).0;
+        ds3231.timeout = timeout;
+        ina219.timeout = timeout;
         let mut s = Self {
             i2c,
             ds3231,
@@ -499,14 +1098,50 @@ impl I2cDevices {
         let i2c = &mut s.i2c;
         s.ina219.write_conf(i2c)?;
         s.ina219.write_calibration(i2c)?;
+        free[sda] = None;
+        free[scl] = None;
         AOk(s)
     }
     fn set_ds3231_rtc(&mut self, dt: &RtcDateTime) -> Result<()> {
-        self.ds3231.set_rtc(&mut self.i2c, dt)
+        let ts = dt.to_unix().ok_or(anyhow::anyhow!("invalid time {dt}"))?;
+        self.ds3231.set_rtc(&mut self.i2c, dt)?;
+        *anyhow_lock(&RTC_SYNC, "set_ds3231_rtc sync")? = Some((ts, Instant::now()));
+        AOk(())
+    }
+    /// Returns the RTC's time as unix seconds; an error if it reads as garbage.
+    fn read_ds3231_rtc(&mut self) -> Result<u32> {
+        let dt = self.ds3231.read_rtc(&mut self.i2c)?;
+        let ts = dt
+            .to_unix()
+            .ok_or(anyhow::anyhow!("RTC holds an invalid time {dt}"))?;
+        *anyhow_lock(&RTC_SYNC, "read_ds3231_rtc sync")? = Some((ts, Instant::now()));
+        AOk(ts)
     }
     fn read_ds3231_rtc_str(&mut self) -> Result<String> {
         self.ds3231.read_rtc_str(&mut self.i2c)
     }
+    /// Reprograms the INA219 with `s`; readings switch to it only once the
+    /// chip has taken both registers, and otherwise it's put back as it was.
+    fn set_ina219(&mut self, s: &Ina219Settings) -> Result<()> {
+        let mut ina219 = INA219::from(s);
+        ina219.timeout = self.ina219.timeout;
+        let i2c = &mut self.i2c;
+        if let Err(e) = ina219
+            .write_conf(i2c)
+            .and_then(|()| ina219.write_calibration(i2c))
+        {
+            if let Err(e) = self
+                .ina219
+                .write_conf(i2c)
+                .and_then(|()| self.ina219.write_calibration(i2c))
+            {
+                log::error!("restoring the INA219 failed: {e}");
+            }
+            return Err(e);
+        }
+        self.ina219 = ina219;
+        AOk(())
+    }
     fn read_ina219_w(&mut self) -> Result<f64> {
         self.ina219.read_w(&mut self.i2c)
     }
@@ -518,25 +1153,37 @@ impl I2cDevices {
     }
 }
 
-fn anyhow_lock<'a, T>(v: &'a Mutex<T>, err_prefix: &'static str) -> Result<MutexGuard<'a, T>> {
-    v.lock()
-        .map_err(|e| anyhow::anyhow!("{err_prefix} anyhow_lock error: {e}"))
-}
-fn record_measurements(i2c: &Mutex<I2cDevices>) -> Result<f64> {
+fn record_measurements(i2c: &Mutex<I2cDevices>, batch: bool) -> Result<f64> {
     let mut i2c = anyhow_lock(i2c, "record_measurements i2c")?;
     let uptime_ms = uptime_usec() / 1000;
-    let rtc_ts = i2c.read_ds3231_rtc_str()?;
+    let rtc_ts = i2c.read_ds3231_rtc()?;
     let w = get_smoothed::<0>(i2c.read_ina219_w()?);
     let v = get_smoothed::<1>(i2c.read_ina219_v()?);
     let a = get_smoothed::<2>(i2c.read_ina219_a()?);
-    let line = format!("{rtc_ts},{w:.2},{v:.2},{a:.3},{uptime_ms}");
+    let r = Record {
+        seq: 0,
+        rtc_ts,
+        w: w as f32,
+        v: v as f32,
+        a: a as f32,
+        uptime_ms: uptime_ms as u64,
+    };
+    let line = r.to_string();
     log::info!("{line}");
-    DATA_FILE.append_data(&line)?;
+    LAST_LINE.set(&line)?;
+    if let Err(e) = Rollups::add(&r) {
+        log::error!("rollup error: {e}");
+    }
+    if RtcRecords::push(r) >= RtcRecords::FLUSH_LEN || !batch {
+        // the records stay buffered for the next attempt; the reading itself is good
+        if let Err(e) = RtcRecords::flush() {
+            log::error!("flushing buffered records failed: {e}");
+        }
+    }
     AOk(v)
 }
-fn setup_wifi<'a>(modem: Modem) -> Result<EspWifi<'a>> {
+fn setup_wifi<'a>(modem: Modem, nvs: EspDefaultNvsPartition) -> Result<EspWifi<'a>> {
     let sys_loop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
     let mut wifi = EspWifi::new(modem, sys_loop, Some(nvs))?;
     let s = SETTINGS_FILE.get()?;
     let conf = WiFiConf::AccessPoint(AccessPointConfiguration {
@@ -561,13 +1208,237 @@ fn setup_wifi<'a>(modem: Modem) -> Result<EspWifi<'a>> {
     wifi.start()?;
     AOk(wifi)
 }
+/// Serves the data log (or the archive, with `archive=1`) in the requested
+/// `format`: as a `.gz` file for the `.csv.gz` route or with `gzip=1`,
+/// gzip-encoded when the client accepts it and isn't resuming, otherwise
+/// uncompressed with `Content-Length` and `Range` support; the
+/// length comes from a counting pass unless a recent response knew it.
+fn get_data(rq: Request<&mut EspHttpConnection>, gz_file: bool) -> Result<()> {
+    let mut range = [None, None];
+    for (i, key) in ["from", "to"].into_iter().enumerate() {
+        let Some(v) = query_param(rq.uri(), key) else {
+            continue;
+        };
+        match RtcDateTime::parse(&v).and_then(|dt| dt.to_unix()) {
+            Some(ts) => range[i] = Some(ts),
+            None => {
+                let mut rs = rq.into_response(400, Some("Bad Request"), &[])?;
+                rs.write(format!("Invalid {key}: expected YYYY-MM-DD HH:MM:SS").as_bytes())?;
+                return AOk(());
+            }
+        }
+    }
+    let fmt = match query_param(rq.uri(), "format") {
+        None => ExportFormat::Csv,
+        Some(v) => match ExportFormat::parse(&v) {
+            Some(fmt) => fmt,
+            None => {
+                let mut rs = rq.into_response(400, Some("Bad Request"), &[])?;
+                rs.write(b"Invalid format: expected csv, jsonl, influx or cbor")?;
+                return AOk(());
+            }
+        },
+    };
+    let archive = query_param(rq.uri(), "archive").is_some_and(|v| v == "1" || v == "true");
+    let mut f = {
+        let data_file = DATA_FILE.lock()?;
+        if !archive {
+            data_file.snapshot()?
+        } else if let Some(a) = data_file.archived()? {
+            a.snapshot()?
+        } else {
+            drop(data_file);
+            let mut rs = rq.into_response(404, Some("Not Found"), &[])?;
+            rs.write(b"No archived data")?;
+            return AOk(());
+        }
+    };
+    let gz_file = gz_file || query_param(rq.uri(), "gzip").is_some_and(|v| v == "1" || v == "true");
+    if gz_file || rq.header("Range").is_none() && accepts_gzip(rq.header("Accept-Encoding")) {
+        let cd = format!("attachment; filename=\"data.{}.gz\"", fmt.file_ext());
+        let headers = if gz_file {
+            vec![
+                ("Content-Type", "application/gzip"),
+                ("Content-Disposition", cd.as_str()),
+            ]
+        } else {
+            vec![
+                ("Content-Type", fmt.content_type()),
+                ("Content-Encoding", "gzip"),
+                ("Vary", "Accept-Encoding"),
+            ]
+        };
+        let mut rs = rq.into_response(200, Some("OK"), &headers)?;
+        let mut gz = GzipEncoder::new(|b| {
+            rs.write(b)?;
+            AOk(())
+        })?;
+        f.read_range(range[0], range[1], fmt, device_id(), |b| gz.write(b))?;
+        return gz.finish();
+    }
+    // an If-Range naming an earlier end of this log still resumes: the
+    // snapshot is cut back to it so the bytes match
+    let if_range = rq.header("If-Range");
+    let resumable = if_range.is_none_or(|v| f.truncate_to(v));
+    if if_range.is_none() && rq.header("Range").is_some() {
+        // a plain resume continues the last download of this URI, as long as
+        // the log has only grown since
+        if let Some(etag) = DOWNLOAD_TOTALS.latest_etag(rq.uri())? {
+            f.truncate_to(&etag);
+        }
+    }
+    let etag = f.etag.clone();
+    let key = format!("{etag} {}", rq.uri());
+    let total = match DOWNLOAD_TOTALS.get(&key)? {
+        Some(total) => total,
+        None => {
+            let mut total = 0;
+            f.read_range(range[0], range[1], fmt, device_id(), |b| {
+                total += b.len() as u64;
+                AOk(())
+            })?;
+            DOWNLOAD_TOTALS.insert(key, total)?;
+            total
+        }
+    };
+    let byte_range = parse_byte_range(rq.header("Range").filter(|_| resumable), total);
+    let (start, end) = match byte_range {
+        ByteRange::Full => (0, total.saturating_sub(1)),
+        ByteRange::Partial(start, end) => (start, end),
+        ByteRange::Unsatisfiable => {
+            let cr = format!("bytes */{total}");
+            rq.into_response(
+                416,
+                Some("Range Not Satisfiable"),
+                &[("Content-Range", &cr)],
+            )?;
+            return AOk(());
+        }
+    };
+    let clen = if total == 0 { 0 } else { end - start + 1 };
+    let clen_s = clen.to_string();
+    let cr = format!("bytes {start}-{end}/{total}");
+    let mut headers = vec![
+        ("Content-Type", fmt.content_type()),
+        ("Content-Length", clen_s.as_str()),
+        ("Accept-Ranges", "bytes"),
+        ("ETag", etag.as_str()),
+        ("Vary", "Accept-Encoding"),
+    ];
+    let mut rs = if let ByteRange::Partial(..) = byte_range {
+        headers.push(("Content-Range", cr.as_str()));
+        rq.into_response(206, Some("Partial Content"), &headers)?
+    } else {
+        rq.into_response(200, Some("OK"), &headers)?
+    };
+    let mut pos = 0;
+    f.read_range(range[0], range[1], fmt, device_id(), |b| {
+        let b_start = pos;
+        pos += b.len() as u64;
+        let lo = start.max(b_start);
+        let hi = (end + 1).min(pos);
+        if clen > 0 && lo < hi {
+            rs.write(&b[(lo - b_start) as usize..(hi - b_start) as usize])?;
+        }
+        AOk(())
+    })?;
+    AOk(())
+}
+fn settings_errors_response(
+    rq: Request<&mut EspHttpConnection>,
+    status: u16,
+    reason: &str,
+    e: &SettingsErrors,
+) -> Result<()> {
+    let mut rs = rq.into_response(
+        status,
+        Some(reason),
+        &[("Content-Type", "application/json")],
+    )?;
+    rs.write(serde_json::to_string(e)?.as_bytes())?;
+    AOk(())
+}
+#[derive(Serialize)]
+struct SettingsUpdated {
+    applied_live: Vec<String>,
+    needs_restart: Vec<String>,
+    restarting: bool,
+}
+/// Validates and saves `s`, the result of merging a partial update into
+/// `cur`, then reports which changed fields were applied live and which wait
+/// for the restart it triggers. INA219 changes are programmed first, so
+/// settings the chip won't take are never saved.
+fn update_settings(
+    rq: Request<&mut EspHttpConnection>,
+    i2c: &Mutex<I2cDevices>,
+    cur: &Settings,
+    s: Result<Settings>,
+    tx: &Sender<Msg>,
+    how: &str,
+) -> Result<()> {
+    let mut s = match s {
+        Ok(s) => s,
+        Err(e) => {
+            if let Some(e) = e.downcast_ref::<SettingsErrors>() {
+                return settings_errors_response(rq, 400, "Bad Request", e);
+            }
+            let mut rs = rq.into_response(400, Some("Bad Request"), &[])?;
+            rs.write(format!("Invalid JSON: {e}").as_bytes())?;
+            return AOk(());
+        }
+    };
+    s.trim();
+    if let Err(e) = s.validate() {
+        return settings_errors_response(rq, 422, "Unprocessable Entity", &e);
+    }
+    if s.ina219 != cur.ina219 {
+        let programmed =
+            anyhow_lock(i2c, "update_settings i2c").and_then(|mut i2c| i2c.set_ina219(&s.ina219));
+        if let Err(e) = programmed {
+            let mut errors = SettingsErrors::default();
+            errors.add("ina219", format!("not accepted by the INA219: {e}"));
+            return settings_errors_response(rq, 422, "Unprocessable Entity", &errors);
+        }
+    }
+    let (applied_live, needs_restart): (Vec<_>, Vec<_>) = cur
+        .changed_fields(&s)?
+        .into_iter()
+        .partition(|f| Settings::applies_live(f));
+    let restarting = !needs_restart.is_empty();
+    let apply_live = !applied_live.is_empty();
+    if apply_live || restarting {
+        SETTINGS_FILE.set(&s)?;
+        let changed = [applied_live.as_slice(), needs_restart.as_slice()].concat();
+        Events::log("settings", &format!("{how} {}", changed.join(" ")));
+    }
+    let mut rs = rq.into_response(200, None, &[("Content-Type", "application/json")])?;
+    rs.write(
+        serde_json::to_string(&SettingsUpdated {
+            applied_live,
+            needs_restart,
+            restarting,
+        })?
+        .as_bytes(),
+    )?;
+    if restarting {
+        tx.send(Msg::Restart("settings changed"))?;
+    } else if apply_live {
+        tx.send(Msg::ApplySettings(s))?;
+    }
+    AOk(())
+}
 fn setup_http<'a>(i2c: Arc<Mutex<I2cDevices>>, tx: Sender<Msg>) -> Result<EspHttpServer<'a>> {
     use embedded_svc::io::Read;
     let get_status_fn_i2c = i2c.clone();
     let set_rtc_fn_i2c = i2c.clone();
+    let set_settings_fn_i2c = i2c.clone();
+    let patch_settings_fn_i2c = i2c.clone();
+    let import_settings_fn_i2c = i2c.clone();
     let get_status_fn_tx = tx.clone();
     let restart_fn_tx = tx.clone();
     let set_settings_fn_tx = tx.clone();
+    let patch_settings_fn_tx = tx.clone();
+    let import_settings_fn_tx = tx.clone();
     let mut http_server = EspHttpServer::new(&HttpConf::default())?;
     http_server.fn_handler("/", HttpMethod::Get, |rq| {
         let mut rs = rq.into_ok_response()?;
@@ -577,7 +1448,7 @@ fn setup_http<'a>(i2c: Arc<Mutex<I2cDevices>>, tx: Sender<Msg>) -> Result<EspHtt
     http_server.fn_handler("/restart", HttpMethod::Get, move |rq| {
         let mut rs = rq.into_ok_response()?;
         rs.write(b"Restarting")?;
-        restart_fn_tx.send(Msg::Restart)?;
+        restart_fn_tx.send(Msg::Restart("requested via /restart"))?;
         AOk(())
     })?;
     #[derive(Serialize, Deserialize)]
@@ -592,7 +1463,7 @@ fn setup_http<'a>(i2c: Arc<Mutex<I2cDevices>>, tx: Sender<Msg>) -> Result<EspHtt
         let mut i2c = anyhow_lock(&get_status_fn_i2c, "get_status i2c")?;
         let s = Status {
             uptime_usec: uptime_usec(),
-            storage_space_info: get_storage_space_info()?,
+            storage_space_info: STORAGE.space_info()?,
             rtc_ts: i2c.read_ds3231_rtc_str()?,
             last_line: LAST_LINE.get()?,
         };
@@ -601,6 +1472,12 @@ fn setup_http<'a>(i2c: Arc<Mutex<I2cDevices>>, tx: Sender<Msg>) -> Result<EspHtt
         get_status_fn_tx.send(Msg::KeepAlive)?;
         AOk(())
     })?;
+    http_server.fn_handler("/get_storage_health", HttpMethod::Get, |rq| {
+        let s = serde_json::to_string(&STORAGE_HEALTH.report()?)?;
+        let mut rs = rq.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+        rs.write(s.as_bytes())?;
+        AOk(())
+    })?;
     http_server.fn_handler("/set_rtc", HttpMethod::Post, move |mut rq| {
         let (h, b) = rq.split();
         let clen = h.content_len().unwrap_or(0) as usize;
@@ -620,24 +1497,80 @@ fn setup_http<'a>(i2c: Arc<Mutex<I2cDevices>>, tx: Sender<Msg>) -> Result<EspHtt
         rs.write(b"RTC updated")?;
         AOk(())
     })?;
-    http_server.fn_handler("/get_data", HttpMethod::Get, |rq| {
+    http_server.fn_handler("/get_data", HttpMethod::Get, |rq| get_data(rq, false))?;
+    http_server.fn_handler("/get_data.csv.gz", HttpMethod::Get, |rq| get_data(rq, true))?;
+    http_server.fn_handler("/get_rollups", HttpMethod::Get, |rq| {
+        let period = query_param(rq.uri(), "period");
+        let Some(p) = period.as_deref().and_then(RollupPeriod::parse) else {
+            let mut rs = rq.into_response(400, Some("Bad Request"), &[])?;
+            rs.write(b"Invalid period: expected hour or day")?;
+            return AOk(());
+        };
         let mut rs = rq.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?;
-        let mut buf = vec![0; 64 * 1024];
-        let f = DATA_FILE.lock()?;
-        let mut f = f.get_file_read();
-        loop {
-            let bytes_read = f.read(&mut buf)?;
-            if bytes_read == 0 {
-                break;
-            }
-            rs.write(&buf[0..bytes_read])?;
-        }
+        Rollups::read(p, |b| {
+            rs.write(b)?;
+            AOk(())
+        })?;
         AOk(())
     })?;
-    http_server.fn_handler("/clear_data", HttpMethod::Get, |rq| {
+    #[derive(Deserialize)]
+    struct ClearDataRq {
+        token: Option<String>,
+        #[serde(default)]
+        archive: bool,
+    }
+    #[derive(Serialize)]
+    struct ClearDataToken {
+        token: String,
+        expires_in_secs: u64,
+    }
+    http_server.fn_handler("/get_events", HttpMethod::Get, |rq| {
+        let mut rs = rq.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?;
+        Events::read(|b| {
+            rs.write(b)?;
+            AOk(())
+        })?;
+        AOk(())
+    })?;
+    http_server.fn_handler("/clear_data", HttpMethod::Post, |mut rq| {
+        let (h, b) = rq.split();
+        let clen = h.content_len().unwrap_or(0) as usize;
+        let mut buf = vec![0u8; clen];
+        b.read_exact(&mut buf)?;
+        let c = match serde_json::from_slice::<ClearDataRq>(if clen == 0 { b"{}" } else { &buf }) {
+            Ok(c) => c,
+            Err(e) => {
+                let mut rs = rq.into_response(400, Some("Bad Request"), &[])?;
+                rs.write(format!("Invalid JSON: {e}").as_bytes())?;
+                return AOk(());
+            }
+        };
+        let Some(token) = c.token else {
+            let t = ClearDataToken {
+                token: CLEAR_DATA_TOKEN.issue()?,
+                expires_in_secs: ConfirmToken::TTL.as_secs(),
+            };
+            let mut rs =
+                rq.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+            rs.write(serde_json::to_string(&t)?.as_bytes())?;
+            return AOk(());
+        };
+        if !CLEAR_DATA_TOKEN.redeem(&token)? {
+            let mut rs = rq.into_response(403, Some("Forbidden"), &[])?;
+            rs.write(b"Invalid or expired token; request a new one")?;
+            return AOk(());
+        }
         let mut rs = rq.into_ok_response()?;
-        DATA_FILE.clear_data()?;
-        rs.write(b"Cleared data")?;
+        DATA_FILE.clear_data(c.archive)?;
+        Events::log(
+            "data_cleared",
+            if c.archive { "archived" } else { "deleted" },
+        );
+        rs.write(if c.archive {
+            b"Archived and cleared data"
+        } else {
+            b"Cleared data"
+        })?;
         AOk(())
     })?;
     http_server.fn_handler("/get_settings", HttpMethod::Get, |rq| {
@@ -647,42 +1580,98 @@ fn setup_http<'a>(i2c: Arc<Mutex<I2cDevices>>, tx: Sender<Msg>) -> Result<EspHtt
         rs.write(s.as_bytes())?;
         AOk(())
     })?;
+    // merged over the current settings like PATCH, so a body naming only
+    // some sections leaves the rest as they are
     http_server.fn_handler("/set_settings", HttpMethod::Post, move |mut rq| {
         let (h, b) = rq.split();
         let clen = h.content_len().unwrap_or(0) as usize;
         let mut buf = vec![0u8; clen];
         b.read_exact(&mut buf)?;
-        let mut s = match serde_json::from_slice::<Settings>(&buf) {
-            Ok(s) => s,
-            Err(e) => {
-                let mut rs = rq.into_response(400, Some("Bad Request"), &[])?;
-                rs.write(format!("Invalid JSON: {e}").as_bytes())?;
-                return AOk(());
-            }
-        };
-        s.wifi_pass = s.wifi_pass.trim().to_string();
-        s.wifi_ssid = s.wifi_ssid.trim().to_string();
-        let mut rs = rq.into_ok_response()?;
-        SETTINGS_FILE.set(&s)?;
-        rs.write(b"Settings updated; Restarting")?;
-        set_settings_fn_tx.send(Msg::Restart)?;
+        let cur = SETTINGS_FILE.get()?;
+        let s = serde_json::from_slice::<serde_json::Value>(&buf)
+            .map_err(anyhow::Error::from)
+            .and_then(|body| cur.merge_patch(&body));
+        update_settings(
+            rq,
+            &set_settings_fn_i2c,
+            &cur,
+            s,
+            &set_settings_fn_tx,
+            "set",
+        )
+    })?;
+    http_server.fn_handler("/set_settings", HttpMethod::Patch, move |mut rq| {
+        let (h, b) = rq.split();
+        let clen = h.content_len().unwrap_or(0) as usize;
+        let mut buf = vec![0u8; clen];
+        b.read_exact(&mut buf)?;
+        let cur = SETTINGS_FILE.get()?;
+        let s = serde_json::from_slice::<serde_json::Value>(&buf)
+            .map_err(anyhow::Error::from)
+            .and_then(|patch| cur.merge_patch(&patch));
+        update_settings(
+            rq,
+            &patch_settings_fn_i2c,
+            &cur,
+            s,
+            &patch_settings_fn_tx,
+            "patched",
+        )
+    })?;
+    http_server.fn_handler("/export_settings", HttpMethod::Get, |rq| {
+        let identity = query_param(rq.uri(), "identity").is_some_and(|v| v == "1" || v == "true");
+        let b = SettingsBundle::export(&SETTINGS_FILE.get()?, identity, device_id())?;
+        let mut rs = rq.into_response(
+            200,
+            None,
+            &[
+                ("Content-Type", "application/json"),
+                (
+                    "Content-Disposition",
+                    "attachment; filename=\"vmon-settings.json\"",
+                ),
+            ],
+        )?;
+        rs.write(serde_json::to_string_pretty(&b)?.as_bytes())?;
         AOk(())
     })?;
+    http_server.fn_handler("/import_settings", HttpMethod::Post, move |mut rq| {
+        let identity = query_param(rq.uri(), "identity").is_some_and(|v| v == "1" || v == "true");
+        let (h, b) = rq.split();
+        let clen = h.content_len().unwrap_or(0) as usize;
+        let mut buf = vec![0u8; clen];
+        b.read_exact(&mut buf)?;
+        let cur = SETTINGS_FILE.get()?;
+        let s = serde_json::from_slice::<SettingsBundle>(&buf)
+            .map_err(anyhow::Error::from)
+            .and_then(|b| b.into_patch(identity))
+            .and_then(|patch| cur.merge_patch(&patch));
+        update_settings(
+            rq,
+            &import_settings_fn_i2c,
+            &cur,
+            s,
+            &import_settings_fn_tx,
+            "imported",
+        )
+    })?;
     AOk(http_server)
 }
 enum Msg {
-    Restart,
+    Restart(&'static str),
     KeepAlive,
+    ApplySettings(Settings),
 }
 struct LaterVars<'a> {
     n: Instant,
+    hi_power_mode_dur: Duration,
+    settings_update: Option<Settings>,
     rx: Receiver<Msg>,
     led: Ws2812Esp32RmtDriver<'a>,
     _w: EspWifi<'a>,
     _h: EspHttpServer<'a>,
 }
 impl<'a> LaterVars<'a> {
-    const HI_POWER_MODE_DUR: Duration = Duration::from_mins(2);
     const LED_STATES: [[u8; 3]; 3] = [[0, 0, 0], [0, 0x20, 0], [0, 0, 0x20]];
     fn set_led_state_log_error(&mut self, state: usize) {
         if let Err(e) = self.led.write_blocking(Self::LED_STATES[state].into_iter()) {
@@ -701,12 +1690,17 @@ impl<'a> LaterVars<'a> {
     fn handle_msgs(&mut self) {
         while let Ok(m) = self.rx.try_recv() {
             match m {
-                Msg::Restart => {
+                Msg::Restart(why) => {
+                    Events::log("restart", why);
                     restart();
                 }
                 Msg::KeepAlive => {
                     self.reset_high_power_mode_timer();
                 }
+                Msg::ApplySettings(s) => {
+                    self.hi_power_mode_dur = Duration::from_secs(s.power.hi_power_mode_secs);
+                    self.settings_update = Some(s);
+                }
             }
         }
     }
@@ -714,12 +1708,12 @@ impl<'a> LaterVars<'a> {
         self.n = Instant::now();
     }
     fn should_end_high_power_mode(&self) -> bool {
-        self.n.elapsed() >= Self::HI_POWER_MODE_DUR
+        self.n.elapsed() >= self.hi_power_mode_dur
     }
 }
 enum Iter<'a> {
     First,
-    NotFirst(LaterVars<'a>),
+    NotFirst(Box<LaterVars<'a>>),
 }
 impl<'a> Iter<'a> {
     fn if_notfirst_take_or_else(self, mut op: impl FnMut() -> Result<Self>) -> Result<Self> {
@@ -746,6 +1740,14 @@ impl<'a> Iter<'a> {
     fn if_notfirst_handle_msgs(&mut self) {
         self.if_notfirst(|vars| vars.handle_msgs());
     }
+    fn if_notfirst_take_settings_update(&mut self) -> Option<Settings> {
+        let mut s = None;
+        self.if_notfirst(|vars| s = vars.settings_update.take());
+        s
+    }
+    fn if_notfirst_check_button(&mut self, button: &BootButton) {
+        self.if_notfirst(|vars| button.check(Some(&mut vars.led)));
+    }
     fn if_notfirst_reset_high_power_mode_timer(&mut self) {
         self.if_notfirst(|vars| vars.reset_high_power_mode_timer());
     }
@@ -779,16 +1781,21 @@ impl Sleeper {
             .max(self.min_sleep)
             .min(self.max_sleep)
     }
-    fn sleep_up_to(&mut self, d: Duration) {
-        let r = self.get_remaining(d);
-        sleep(r);
+    /// Returns early once `stop` is true, checking it every `POLL`.
+    fn sleep_up_to(&mut self, d: Duration, mut stop: impl FnMut() -> bool) {
+        const POLL: Duration = Duration::from_millis(50);
+        let end = Instant::now() + self.get_remaining(d);
+        while !stop() {
+            let left = end.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            sleep(left.min(POLL));
+        }
     }
     fn reset_then_sleep_up_to(&mut self, d: Duration) {
         let r = self.get_remaining(d);
-        reset_then_sleep(
-            u64::try_from(r.as_micros())
-                .expect("failure casting remaining duration to u64 in reset_then_sleep_up_to"),
-        );
+        reset_then_sleep(u64::try_from(r.as_micros()).unwrap_or(u64::MAX));
     }
 }
 struct SleeperWithPresets {
@@ -798,25 +1805,25 @@ struct SleeperWithPresets {
     very_low_power_dur: Duration,
 }
 impl SleeperWithPresets {
-    fn new(
-        min_sleep: Duration,
-        max_sleep: Duration,
-        short_sleep_dur: Duration,
-        low_power_dur: Duration,
-        very_low_power_dur: Duration,
-    ) -> Self {
+    fn new(p: &PowerSettings) -> Self {
+        let secs = Duration::from_secs;
         Self {
-            sleeper: Sleeper::new(min_sleep, max_sleep),
-            short_sleep_dur,
-            low_power_dur,
-            very_low_power_dur,
+            sleeper: Sleeper::new(secs(p.min_sleep_secs), secs(p.max_sleep_secs)),
+            short_sleep_dur: secs(p.record_sleep_secs),
+            low_power_dur: secs(p.record_sleep_secs),
+            very_low_power_dur: secs(p.lo_v_sleep_secs),
         }
     }
+    /// Swaps in new presets, keeping the start of the current sleep cycle.
+    fn set_power(&mut self, p: &PowerSettings) {
+        let old = std::mem::replace(self, Self::new(p));
+        self.sleeper.t0 = old.sleeper.t0;
+    }
     fn set_t0_now_sub_if_unset(&mut self, sub: Duration) {
         self.sleeper.set_t0_now_sub_if_unset(sub);
     }
-    fn short_sleep(&mut self) {
-        self.sleeper.sleep_up_to(self.short_sleep_dur);
+    fn short_sleep(&mut self, stop: impl FnMut() -> bool) {
+        self.sleeper.sleep_up_to(self.short_sleep_dur, stop);
     }
     fn enter_low_power(&mut self) {
         self.sleeper.reset_then_sleep_up_to(self.low_power_dur);
@@ -825,14 +1832,106 @@ impl SleeperWithPresets {
         self.sleeper.reset_then_sleep_up_to(self.very_low_power_dur);
     }
 }
-fn enter_very_low_power<'a>(iter: &mut Iter<'a>, sleeper: &mut SleeperWithPresets) {
+fn enter_very_low_power<'a>(iter: &mut Iter<'a>, sleeper: &mut SleeperWithPresets, why: &str) {
+    PowerMode::VeryLow.note(why);
     iter.if_notfirst_led_state_0();
     sleeper.enter_very_low_power();
 }
-fn enter_low_power<'a>(iter: &mut Iter<'a>, sleeper: &mut SleeperWithPresets) {
+fn enter_low_power<'a>(iter: &mut Iter<'a>, sleeper: &mut SleeperWithPresets, why: &str) {
+    PowerMode::Low.note(why);
     iter.if_notfirst_led_state_0();
     sleeper.enter_low_power();
 }
+#[derive(Clone, Copy, PartialEq)]
+enum ButtonHold {
+    Short,
+    ResetSettings,
+    ClearData,
+}
+/// The BOOT button (GPIO9, low while pressed). It must be pressed after reset,
+/// since holding it through one selects the ROM download mode instead.
+struct BootButton<'a> {
+    pin: PinDriver<'a, Gpio9, Input>,
+}
+impl<'a> BootButton<'a> {
+    const RESET_SETTINGS_HOLD: Duration = Duration::from_secs(5);
+    const CLEAR_DATA_HOLD: Duration = Duration::from_secs(15);
+    const POLL: Duration = Duration::from_millis(50);
+    fn new(pin: Gpio9) -> Result<Self> {
+        let mut pin = PinDriver::input(pin)?;
+        pin.set_pull(Pull::Up)?;
+        AOk(Self { pin })
+    }
+    fn is_pressed(&self) -> bool {
+        self.pin.is_low()
+    }
+    fn hold(held: Duration) -> ButtonHold {
+        if held >= Self::CLEAR_DATA_HOLD {
+            ButtonHold::ClearData
+        } else if held >= Self::RESET_SETTINGS_HOLD {
+            ButtonHold::ResetSettings
+        } else {
+            ButtonHold::Short
+        }
+    }
+    /// GRB, like `LaterVars::LED_STATES`: amber once releasing would reset
+    /// settings, white once it would clear data too.
+    fn led_color(hold: ButtonHold) -> Option<[u8; 3]> {
+        match hold {
+            ButtonHold::Short => None,
+            ButtonHold::ResetSettings => Some([0x10, 0x20, 0]),
+            ButtonHold::ClearData => Some([0x20, 0x20, 0x20]),
+        }
+    }
+    /// If the button is down, waits for its release and then does the reset
+    /// the hold time selects, restarting afterwards; a short press does nothing.
+    fn check(&self, mut led: Option<&mut Ws2812Esp32RmtDriver<'_>>) {
+        if !self.is_pressed() {
+            return;
+        }
+        let t0 = Instant::now();
+        let mut shown = ButtonHold::Short;
+        while self.is_pressed() {
+            feed_watchdog();
+            let hold = Self::hold(t0.elapsed());
+            if hold != shown {
+                shown = hold;
+                if let (Some(led), Some(c)) = (led.as_deref_mut(), Self::led_color(hold)) {
+                    if let Err(e) = led.write_blocking(c.into_iter()) {
+                        log::warn!("error set led for button hold: {e}");
+                    }
+                }
+            }
+            sleep(Self::POLL);
+        }
+        let hold = Self::hold(t0.elapsed());
+        if hold == ButtonHold::Short {
+            return;
+        }
+        if let Err(e) = factory_reset(hold == ButtonHold::ClearData) {
+            log::error!("factory reset failed: {e}");
+        }
+        restart();
+    }
+}
+/// Puts back the default settings (and so the default AP credentials), and
+/// with `clear_data` deletes the logged data as well.
+fn factory_reset(clear_data: bool) -> Result<()> {
+    log::warn!("factory reset; clear_data={clear_data}");
+    SETTINGS_FILE.set(&Settings::default())?;
+    if clear_data {
+        DATA_FILE.clear_data(false)?;
+    }
+    Events::log(
+        "factory_reset",
+        if clear_data {
+            "settings and data"
+        } else {
+            "settings"
+        },
+    );
+    AOk(())
+}
 fn init_led<'a, C: RmtChannel>(
     channel: impl Peripheral<P = C> + 'a,
     pin: impl Peripheral<P = impl OutputPin> + 'a,
@@ -841,67 +1940,159 @@ fn init_led<'a, C: RmtChannel>(
     led.write_blocking([0, 0, 0].into_iter())?;
     AOk(Some(led))
 }
+/// Brings up the I2C devices with the stored board profile and INA219
+/// settings, or failing that the defaults, so settings that don't work on
+/// this board can't keep the unit off the network until the button is held.
+fn init_i2c(
+    i2c0: &mut I2C0,
+    free: &mut [Option<AnyIOPin>],
+    board: &BoardSettings,
+    ina219: &Ina219Settings,
+) -> Result<I2cDevices> {
+    let (default_board, default_ina219) = (BoardSettings::default(), Ina219Settings::default());
+    let mut tries = vec![
+        (board, ina219),
+        (board, &default_ina219),
+        (&default_board, &default_ina219),
+    ];
+    tries.dedup();
+    let mut err = None;
+    for (board, ina219) in tries {
+        if err.is_some() {
+            log::warn!("retrying I2C init with default settings");
+        }
+        match I2cDevices::new(i2c0, free, board, DS3231::new(0x68), INA219::from(ina219)) {
+            Ok(d) => return AOk(d),
+            Err(e) => {
+                log::error!("I2C init failed: {e}");
+                Events::log("i2c", &format!("init failed: {e}"));
+                err = Some(e);
+            }
+        }
+    }
+    Err(err.expect("at least one try"))
+}
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
     log::set_max_level(log::LevelFilter::Debug);
-    const HI_V: f64 = 13.0;
-    const LO_V: f64 = 12.2;
-    const MAX_SLEEP_DUR: Duration = Duration::from_secs(60);
-    const LO_V_SLEEP_DUR: Duration = Duration::from_secs(60);
-    const RECORD_SLEEP_DUR: Duration = Duration::from_secs(10);
-    const MIN_SLEEP_DUR: Duration = Duration::from_secs(5);
-    let mut sleeper = SleeperWithPresets::new(
-        MIN_SLEEP_DUR,
-        MAX_SLEEP_DUR,
-        RECORD_SLEEP_DUR,
-        RECORD_SLEEP_DUR,
-        LO_V_SLEEP_DUR,
-    );
-    sleeper.set_t0_now_sub_if_unset(Duration::from_micros(uptime_usec() as u64));
     feed_watchdog();
+    let nvs = EspDefaultNvsPartition::take()?;
+    if let Err(e) = STORAGE_HEALTH.init(nvs.clone()) {
+        log::error!("storage health init failed: {e}");
+    }
+    if let Err(e) = SETTINGS_FILE.init(nvs.clone()) {
+        log::error!("settings NVS init failed: {e}");
+    }
     let _storage = mount_storage()?;
+    let settings = SETTINGS_FILE.get().unwrap_or_else(|e| {
+        log::error!("settings unreadable: {e}; using defaults");
+        Settings::default()
+    });
+    let data = match settings.data.validate() {
+        Ok(()) => settings.data,
+        Err(e) => {
+            log::error!("data settings unusable: {e}; using defaults");
+            DataSettings::default()
+        }
+    };
+    DATA_FILE.init(data.retention())?;
+    let mut power = match settings.power.validate() {
+        Ok(()) => settings.power,
+        Err(e) => {
+            log::error!("power settings unusable: {e}; using defaults");
+            PowerSettings::default()
+        }
+    };
+    let ina219 = match settings.ina219.validate() {
+        Ok(()) => settings.ina219,
+        Err(e) => {
+            log::error!("ina219 settings unusable: {e}; using defaults");
+            Ina219Settings::default()
+        }
+    };
+    let board = match settings.board.validate() {
+        Ok(()) => settings.board,
+        Err(e) => {
+            log::error!("board settings unusable: {e}; using defaults");
+            BoardSettings::default()
+        }
+    };
+    let mut sleeper = SleeperWithPresets::new(&power);
+    sleeper.set_t0_now_sub_if_unset(Duration::from_micros(uptime_usec() as u64));
     let peripherals = Peripherals::take()?;
     let mut led = init_led(peripherals.rmt.channel0, peripherals.pins.gpio8)?;
-    let i2c = I2cDevices::new(
-        I2cDriver::new(
-            peripherals.i2c0,
-            peripherals.pins.gpio3,
-            peripherals.pins.gpio2,
-            &I2cConfig::new().baudrate(400.kHz().into()),
-        )?,
-        DS3231::new(0x68),
-        INA219::new(0x41, 0.1, 3.2, 0x3FFF), // 0x3FFF based on https://www.ti.com/lit/ds/symlink/ina219.pdf
-    )?;
+    let button = BootButton::new(peripherals.pins.gpio9)?;
+    button.check(led.as_mut());
+    let mut free_pins = [
+        peripherals.pins.gpio0.downgrade(),
+        peripherals.pins.gpio1.downgrade(),
+        peripherals.pins.gpio2.downgrade(),
+        peripherals.pins.gpio3.downgrade(),
+        peripherals.pins.gpio4.downgrade(),
+        peripherals.pins.gpio5.downgrade(),
+        peripherals.pins.gpio6.downgrade(),
+        peripherals.pins.gpio7.downgrade(),
+        peripherals.pins.gpio10.downgrade(),
+    ]
+    .map(Some);
+    let mut i2c0 = peripherals.i2c0;
+    let mut i2c = init_i2c(&mut i2c0, &mut free_pins, &board, &ina219)?;
+    if !woke_from_sleep() {
+        if let Err(e) = i2c.read_ds3231_rtc() {
+            log::error!("RTC read at boot failed: {e}");
+        }
+        Events::log("boot", &format!("reset={}", reset_reason()));
+    }
     let i2c = Arc::new(Mutex::new(i2c));
     let mut woke_from_sleep_and_below_hi_v = woke_from_sleep();
     let mut wifi_modem = Some(peripherals.modem);
     let mut iter = Iter::First;
+    let hi_power_mode_dur = Duration::from_secs(power.hi_power_mode_secs);
     let mut mk_notfirst = || {
         let (tx, rx) = channel();
-        let _w = setup_wifi(wifi_modem.take().expect("wifi_modem is taken once"))?;
+        let _w = setup_wifi(
+            wifi_modem.take().expect("wifi_modem is taken once"),
+            nvs.clone(),
+        )?;
         let _h = setup_http(i2c.clone(), tx)?;
         let n = Instant::now();
         let led = led.take().expect("led is taken once");
-        let mut vars = LaterVars { rx, led, _w, _h, n };
+        let mut vars = LaterVars {
+            rx,
+            led,
+            _w,
+            _h,
+            n,
+            hi_power_mode_dur,
+            settings_update: None,
+        };
         vars.set_led_state_2();
-        AOk(Iter::NotFirst(vars))
+        AOk(Iter::NotFirst(Box::new(vars)))
     };
     loop {
         sleeper.set_t0_now_sub_if_unset(Duration::ZERO);
         feed_watchdog();
         iter.if_notfirst_led_state_1();
-        match record_measurements(&i2c) {
+        let measured = record_measurements(&i2c, woke_from_sleep_and_below_hi_v);
+        note_measurement_result(&measured);
+        match measured {
             Err(e) => {
                 log::error!("record_measurements error: {e}");
-                enter_very_low_power(&mut iter, &mut sleeper);
+                enter_very_low_power(&mut iter, &mut sleeper, "measurement error");
             }
             Ok(v) => {
-                if v <= LO_V {
-                    enter_very_low_power(&mut iter, &mut sleeper);
-                } else if v < HI_V && woke_from_sleep_and_below_hi_v {
-                    enter_low_power(&mut iter, &mut sleeper);
-                } else if v >= HI_V {
+                if v <= power.lo_v {
+                    enter_very_low_power(&mut iter, &mut sleeper, &format!("v={v:.2}"));
+                } else if v < power.hi_v && woke_from_sleep_and_below_hi_v {
+                    enter_low_power(&mut iter, &mut sleeper, &format!("v={v:.2}"));
+                } else if v >= power.hi_v {
+                    PowerMode::High.note(&format!("v={v:.2}"));
+                    if woke_from_sleep_and_below_hi_v {
+                        if let Err(e) = RtcRecords::flush() {
+                            log::error!("flush on entering high power mode failed: {e}");
+                        }
+                    }
                     woke_from_sleep_and_below_hi_v = false;
                     iter.if_notfirst_reset_high_power_mode_timer();
                 }
@@ -909,10 +2100,16 @@ fn main() -> Result<()> {
         }
         iter.if_notfirst_led_state_2();
         iter.if_notfirst_handle_msgs();
+        iter.if_notfirst_check_button(&button);
+        if let Some(s) = iter.if_notfirst_take_settings_update() {
+            // the INA219 was reprogrammed before the settings were saved
+            sleeper.set_power(&s.power);
+            power = s.power;
+        }
         if iter.should_end_notfirst_high_power_mode() {
-            enter_low_power(&mut iter, &mut sleeper);
+            enter_low_power(&mut iter, &mut sleeper, "high power mode timed out");
         }
         iter = iter.if_notfirst_take_or_else(&mut mk_notfirst)?;
-        sleeper.short_sleep();
+        sleeper.short_sleep(|| button.is_pressed());
     }
 }
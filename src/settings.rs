@@ -0,0 +1,992 @@
+use crate::data::RetentionPolicy;
+use crate::storage::Storage;
+use crate::storage::STOR_MIN_FREE;
+use anyhow::Ok as AOk;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+// relative to the `Storage` root
+const SETTINGS_FILE_PATH: &str = "settings.json";
+const SETTINGS_TMP_FILE_PATH: &str = "settings.json.tmp";
+const SETTINGS_BAK_FILE_PATH: &str = "settings.json.bak";
+/// Missing fields take their defaults, so settings written by older firmware
+/// still load; `SettingsFile` migrates them by `version` first.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Absent in files from before versioning, which are version 1.
+    #[serde(default = "Settings::unversioned")]
+    pub version: u32,
+    pub wifi_pass: String,
+    pub wifi_ssid: String,
+    pub power: PowerSettings,
+    pub ina219: Ina219Settings,
+    pub board: BoardSettings,
+    pub data: DataSettings,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: Self::VERSION,
+            wifi_pass: "kspass1234".to_string(),
+            wifi_ssid: "ESP2".to_string(),
+            power: PowerSettings::default(),
+            ina219: Ina219Settings::default(),
+            board: BoardSettings::default(),
+            data: DataSettings::default(),
+        }
+    }
+}
+type SettingsMigration = fn(&mut serde_json::Map<String, serde_json::Value>);
+impl Settings {
+    pub const VERSION: u32 = 5;
+    /// `MIGRATIONS[i]` upgrades a version `i + 1` object to version `i + 2`.
+    const MIGRATIONS: [SettingsMigration; Self::VERSION as usize - 1] = [
+        // 1 -> 2: `power` was added; its defaults fill in
+        |_| {},
+        // 2 -> 3: `ina219` was added; its defaults are the old hardcoded values
+        |_| {},
+        // 3 -> 4: `board` was added; its defaults are the first board revision's
+        |_| {},
+        // 4 -> 5: `data` was added; its defaults are the old fixed retention
+        |_| {},
+    ];
+    fn unversioned() -> u32 {
+        1
+    }
+    /// Parses settings written by any firmware version, applying the
+    /// migrations from theirs; returns whether any were applied.
+    fn parse_migrating(str: &str) -> Result<(Self, bool)> {
+        let mut v: serde_json::Value = serde_json::from_str(str)?;
+        let obj = v
+            .as_object_mut()
+            .ok_or(anyhow::anyhow!("expected a JSON object"))?;
+        let migrated = Self::migrate(obj);
+        AOk((serde_json::from_value(v)?, migrated))
+    }
+    /// Brings a settings object of any version up to `VERSION`; returns
+    /// whether it was older.
+    fn migrate(obj: &mut serde_json::Map<String, serde_json::Value>) -> bool {
+        let from = obj
+            .get("version")
+            .and_then(serde_json::Value::as_u64)
+            .map_or(Self::unversioned(), |v| v as u32);
+        if from > Self::VERSION {
+            log::warn!(
+                "settings are version {from}, newer than {}; ignoring unknown fields",
+                Self::VERSION
+            );
+        }
+        for (i, m) in Self::MIGRATIONS.iter().enumerate() {
+            let to = i as u32 + 2;
+            if from < to {
+                log::info!("migrating settings to version {to}");
+                m(obj);
+                obj.insert("version".to_string(), to.into());
+            }
+        }
+        from < Self::VERSION
+    }
+    /// Fields that set one unit apart from another, left out of bundles
+    /// unless asked for: the AP's SSID is how units are told apart.
+    const IDENTITY_FIELDS: [&str; 1] = ["wifi_ssid"];
+    /// Fields whose dotted path starts with one of these are picked up by the
+    /// main loop without a restart; everything else takes effect on boot.
+    const LIVE_PREFIXES: [&str; 2] = ["power.", "ina219."];
+    pub fn applies_live(path: &str) -> bool {
+        Self::LIVE_PREFIXES.iter().any(|p| path.starts_with(p))
+    }
+    pub fn trim(&mut self) {
+        self.wifi_pass = self.wifi_pass.trim().to_string();
+        self.wifi_ssid = self.wifi_ssid.trim().to_string();
+    }
+    /// Applies an RFC 7396 merge patch: objects merge member by member and a
+    /// `null` member is removed, so it falls back to its default. `version`
+    /// can't be patched. Members that aren't settings fail with a
+    /// `SettingsErrors` rather than being dropped.
+    pub fn merge_patch(&self, patch: &serde_json::Value) -> Result<Self> {
+        fn unknown(
+            known: &serde_json::Value,
+            patch: &serde_json::Value,
+            path: &str,
+            e: &mut SettingsErrors,
+        ) {
+            let (Some(known), Some(patch)) = (known.as_object(), patch.as_object()) else {
+                return;
+            };
+            for (k, v) in patch {
+                let sub = if path.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{path}.{k}")
+                };
+                match known.get(k) {
+                    Some(known) => unknown(known, v, &sub, e),
+                    None => e.add(&sub, "is not a setting"),
+                }
+            }
+        }
+        fn merge(target: &mut serde_json::Value, patch: &serde_json::Value) {
+            let serde_json::Value::Object(p) = patch else {
+                *target = patch.clone();
+                return;
+            };
+            if !target.is_object() {
+                *target = serde_json::Value::Object(serde_json::Map::new());
+            }
+            let t = target.as_object_mut().expect("target is an object");
+            for (k, v) in p {
+                if v.is_null() {
+                    t.remove(k);
+                } else {
+                    merge(t.entry(k.clone()).or_insert(serde_json::Value::Null), v);
+                }
+            }
+        }
+        if !patch.is_object() {
+            anyhow::bail!("expected a JSON object");
+        }
+        let mut v = serde_json::to_value(self)?;
+        let mut e = SettingsErrors::default();
+        unknown(&v, patch, "", &mut e);
+        e.into_result()?;
+        merge(&mut v, patch);
+        let mut s: Self = serde_json::from_value(v)?;
+        s.version = self.version;
+        AOk(s)
+    }
+    /// Dotted paths of the fields that differ between `self` and `other`.
+    pub fn changed_fields(&self, other: &Self) -> Result<Vec<String>> {
+        fn diff(a: &serde_json::Value, b: &serde_json::Value, path: &str, out: &mut Vec<String>) {
+            match (a, b) {
+                (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+                    let keys: std::collections::BTreeSet<_> = a.keys().chain(b.keys()).collect();
+                    let null = serde_json::Value::Null;
+                    for k in keys {
+                        let sub = if path.is_empty() {
+                            k.clone()
+                        } else {
+                            format!("{path}.{k}")
+                        };
+                        diff(
+                            a.get(k).unwrap_or(&null),
+                            b.get(k).unwrap_or(&null),
+                            &sub,
+                            out,
+                        );
+                    }
+                }
+                _ if a != b => out.push(path.to_string()),
+                _ => {}
+            }
+        }
+        let mut out = Vec::new();
+        diff(
+            &serde_json::to_value(self)?,
+            &serde_json::to_value(other)?,
+            "",
+            &mut out,
+        );
+        AOk(out)
+    }
+    /// SSID and passphrase limits are those of an ESP32 WPA2 access point.
+    pub fn validate(&self) -> Result<(), SettingsErrors> {
+        let mut e = SettingsErrors::default();
+        if self.wifi_ssid.is_empty() || self.wifi_ssid.len() > 32 {
+            e.add("wifi_ssid", "must be 1 to 32 bytes");
+        }
+        let pass = &self.wifi_pass;
+        let is_hex_key = pass.len() == 64 && pass.bytes().all(|b| b.is_ascii_hexdigit());
+        if !is_hex_key && !(8..=63).contains(&pass.len()) {
+            e.add(
+                "wifi_pass",
+                "must be 8 to 63 characters (or a 64-digit hex key)",
+            );
+        } else if !pass.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+            e.add("wifi_pass", "must be printable ASCII");
+        }
+        self.power.check(&mut e);
+        self.ina219.check(&mut e);
+        self.board.check(&mut e);
+        self.data.check(&mut e);
+        e.into_result()
+    }
+}
+/// Voltage thresholds and intervals for switching between power modes.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PowerSettings {
+    /// At or above this, Wi-Fi and HTTP are brought up (high-power mode).
+    pub hi_v: f64,
+    /// At or below this, sleep `lo_v_sleep_secs` between measurements.
+    pub lo_v: f64,
+    pub min_sleep_secs: u64,
+    pub max_sleep_secs: u64,
+    pub lo_v_sleep_secs: u64,
+    pub record_sleep_secs: u64,
+    /// How long high-power mode lasts without a request from a client.
+    pub hi_power_mode_secs: u64,
+}
+impl Default for PowerSettings {
+    fn default() -> Self {
+        Self {
+            hi_v: 13.0,
+            lo_v: 12.2,
+            min_sleep_secs: 5,
+            max_sleep_secs: 60,
+            lo_v_sleep_secs: 60,
+            record_sleep_secs: 10,
+            hi_power_mode_secs: 120,
+        }
+    }
+}
+impl PowerSettings {
+    const MIN_HI_POWER_MODE_SECS: u64 = 30;
+    /// Upper bound for every interval, so a typo can't park the device for years.
+    const MAX_SECS: u64 = 24 * 60 * 60;
+    /// The INA219's bus range with the default `conf`; a higher `hi_v` could
+    /// never be measured.
+    const BUS_MAX_V: f64 = 32.0;
+    fn check(&self, e: &mut SettingsErrors) {
+        let sleep_range = self.min_sleep_secs..=self.max_sleep_secs;
+        if !(self.hi_v.is_finite() && self.hi_v > 0.0) {
+            e.add("power.hi_v", "must be a positive number");
+        } else if self.hi_v > Self::BUS_MAX_V {
+            e.add(
+                "power.hi_v",
+                format!("must be at most {} (the INA219 bus range)", Self::BUS_MAX_V),
+            );
+        }
+        if !(self.lo_v.is_finite() && self.lo_v > 0.0) {
+            e.add("power.lo_v", "must be a positive number");
+        } else if self.lo_v >= self.hi_v {
+            e.add("power.lo_v", "must be less than hi_v");
+        }
+        if self.min_sleep_secs == 0 {
+            e.add("power.min_sleep_secs", "must be at least 1");
+        }
+        if self.max_sleep_secs < self.min_sleep_secs {
+            e.add("power.max_sleep_secs", "must be at least min_sleep_secs");
+        }
+        for (field, secs) in [
+            ("power.max_sleep_secs", self.max_sleep_secs),
+            ("power.lo_v_sleep_secs", self.lo_v_sleep_secs),
+            ("power.record_sleep_secs", self.record_sleep_secs),
+            ("power.hi_power_mode_secs", self.hi_power_mode_secs),
+        ] {
+            if secs > Self::MAX_SECS {
+                e.add(field, format!("must be at most {} (1 day)", Self::MAX_SECS));
+            }
+        }
+        for (field, secs) in [
+            ("power.lo_v_sleep_secs", self.lo_v_sleep_secs),
+            ("power.record_sleep_secs", self.record_sleep_secs),
+        ] {
+            if !sleep_range.contains(&secs) {
+                e.add(field, "must be between min_sleep_secs and max_sleep_secs");
+            }
+        }
+        if self.hi_power_mode_secs < Self::MIN_HI_POWER_MODE_SECS {
+            e.add(
+                "power.hi_power_mode_secs",
+                format!("must be at least {}", Self::MIN_HI_POWER_MODE_SECS),
+            );
+        }
+    }
+    pub fn validate(&self) -> Result<(), SettingsErrors> {
+        let mut e = SettingsErrors::default();
+        self.check(&mut e);
+        e.into_result()
+    }
+}
+/// How the INA219 is wired up: its I2C address, the shunt it measures across,
+/// and its configuration register.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ina219Settings {
+    pub addr: u8,
+    pub shunt_ohms: f64,
+    /// Sets the current resolution, `max_current_a / 2^15`.
+    pub max_current_a: f64,
+    pub conf: u16,
+}
+impl Default for Ina219Settings {
+    fn default() -> Self {
+        Self {
+            addr: 0x41,
+            shunt_ohms: 0.1,
+            max_current_a: 3.2,
+            conf: 0x3FFF, // based on https://www.ti.com/lit/ds/symlink/ina219.pdf
+        }
+    }
+}
+impl Ina219Settings {
+    /// A0 and A1 select one of 16 addresses.
+    const ADDRS: std::ops::RangeInclusive<u8> = 0x40..=0x4F;
+    const CONF_RESET: u16 = 0x8000;
+    const INTERNAL_FIXED_VALUE: f64 = 0.04096;
+    pub fn current_lsb(max_current_a: f64) -> f64 {
+        max_current_a / 2_f64.powi(15)
+    }
+    /// The calibration register value for a shunt and current range.
+    pub fn calibration(shunt_ohms: f64, max_current_a: f64) -> f64 {
+        (Self::INTERNAL_FIXED_VALUE / (Self::current_lsb(max_current_a) * shunt_ohms)).trunc()
+    }
+    fn check(&self, e: &mut SettingsErrors) {
+        if !Self::ADDRS.contains(&self.addr) {
+            e.add("ina219.addr", "must be 0x40 to 0x4F (64 to 79)");
+        }
+        if !(self.shunt_ohms.is_finite() && self.shunt_ohms > 0.0) {
+            e.add("ina219.shunt_ohms", "must be a positive number");
+        } else if !(self.max_current_a.is_finite() && self.max_current_a > 0.0) {
+            e.add("ina219.max_current_a", "must be a positive number");
+        } else {
+            // bit 0 of the calibration register is always 0
+            let cal = Self::calibration(self.shunt_ohms, self.max_current_a);
+            if !(2.0..=f64::from(u16::MAX - 1)).contains(&cal) {
+                e.add(
+                    "ina219.max_current_a",
+                    format!("gives calibration {cal:.0} for this shunt; must be 2 to 65534"),
+                );
+            }
+        }
+        if self.conf & Self::CONF_RESET != 0 {
+            e.add("ina219.conf", "must not set the reset bit (0x8000)");
+        }
+    }
+    pub fn validate(&self) -> Result<(), SettingsErrors> {
+        let mut e = SettingsErrors::default();
+        self.check(&mut e);
+        e.into_result()
+    }
+}
+/// Wiring that differs between board revisions; applied at boot. If the I2C
+/// devices don't come up with it, boot carries on with the defaults.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BoardSettings {
+    pub i2c_sda: u8,
+    pub i2c_scl: u8,
+    pub i2c_hz: u32,
+    /// Per-transaction timeout for the I2C devices.
+    pub i2c_timeout_ms: u32,
+}
+impl Default for BoardSettings {
+    fn default() -> Self {
+        Self {
+            i2c_sda: 3,
+            i2c_scl: 2,
+            i2c_hz: 400_000,
+            i2c_timeout_ms: 100,
+        }
+    }
+}
+impl BoardSettings {
+    /// ESP32-C3 GPIOs free for I2C: 8 is the LED, 9 the BOOT button, 11-17
+    /// the SPI flash, 18-19 the USB serial/JTAG console and 20-21 UART0.
+    pub const I2C_PINS: [u8; 9] = [0, 1, 2, 3, 4, 5, 6, 7, 10];
+    const I2C_HZ: std::ops::RangeInclusive<u32> = 10_000..=800_000;
+    const I2C_TIMEOUT_MS: std::ops::RangeInclusive<u32> = 1..=1000;
+    fn check(&self, e: &mut SettingsErrors) {
+        for (field, pin) in [
+            ("board.i2c_sda", self.i2c_sda),
+            ("board.i2c_scl", self.i2c_scl),
+        ] {
+            if !Self::I2C_PINS.contains(&pin) {
+                e.add(field, format!("must be one of GPIO {:?}", Self::I2C_PINS));
+            }
+        }
+        if self.i2c_sda == self.i2c_scl {
+            e.add("board.i2c_scl", "must differ from i2c_sda");
+        }
+        if !Self::I2C_HZ.contains(&self.i2c_hz) {
+            e.add("board.i2c_hz", "must be 10000 to 800000");
+        }
+        if !Self::I2C_TIMEOUT_MS.contains(&self.i2c_timeout_ms) {
+            e.add("board.i2c_timeout_ms", "must be 1 to 1000");
+        }
+    }
+    pub fn validate(&self) -> Result<(), SettingsErrors> {
+        let mut e = SettingsErrors::default();
+        self.check(&mut e);
+        e.into_result()
+    }
+}
+/// How much measurement data is kept on flash; applied at boot.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DataSettings {
+    pub segment_kib: u32,
+    /// The oldest segments beyond this many are removed.
+    pub max_segments: u32,
+    /// The oldest segments are removed while less than this is free.
+    pub min_free_kib: u32,
+}
+impl Default for DataSettings {
+    fn default() -> Self {
+        Self {
+            segment_kib: 64,
+            max_segments: 256,
+            min_free_kib: (STOR_MIN_FREE / 1024) as u32,
+        }
+    }
+}
+impl DataSettings {
+    const SEGMENT_KIB: std::ops::RangeInclusive<u32> = 4..=1024;
+    const MAX_SEGMENTS: std::ops::RangeInclusive<u32> = 2..=4096;
+    /// Below `STOR_MIN_FREE` appends fail before retention frees anything.
+    const MIN_FREE_KIB: std::ops::RangeInclusive<u32> = (STOR_MIN_FREE / 1024) as u32..=16 * 1024;
+    fn check(&self, e: &mut SettingsErrors) {
+        if !Self::SEGMENT_KIB.contains(&self.segment_kib) {
+            e.add("data.segment_kib", "must be 4 to 1024");
+        }
+        if !Self::MAX_SEGMENTS.contains(&self.max_segments) {
+            e.add("data.max_segments", "must be 2 to 4096");
+        }
+        if !Self::MIN_FREE_KIB.contains(&self.min_free_kib) {
+            e.add(
+                "data.min_free_kib",
+                format!("must be {} to 16384", Self::MIN_FREE_KIB.start()),
+            );
+        }
+    }
+    pub fn validate(&self) -> Result<(), SettingsErrors> {
+        let mut e = SettingsErrors::default();
+        self.check(&mut e);
+        e.into_result()
+    }
+    pub fn retention(&self) -> RetentionPolicy {
+        RetentionPolicy::new(
+            self.segment_kib as u64 * 1024,
+            self.max_segments as usize,
+            self.min_free_kib as usize * 1024,
+        )
+    }
+}
+/// Validation failures keyed by the field's dotted path, first failure per field.
+#[derive(Debug, Default, Serialize)]
+pub struct SettingsErrors {
+    errors: std::collections::BTreeMap<String, String>,
+}
+impl SettingsErrors {
+    pub fn add(&mut self, field: &str, msg: impl Into<String>) {
+        self.errors
+            .entry(field.to_string())
+            .or_insert_with(|| msg.into());
+    }
+    fn into_result(self) -> Result<(), Self> {
+        if self.errors.is_empty() {
+            std::result::Result::Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+impl std::fmt::Display for SettingsErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (field, msg)) in self.errors.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "; " };
+            write!(f, "{sep}{field} {msg}")?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+impl std::error::Error for SettingsErrors {}
+/// Settings exported from one unit for importing into others.
+#[derive(Serialize, Deserialize)]
+pub struct SettingsBundle {
+    format: String,
+    version: u32,
+    /// The unit the bundle came from; informational only.
+    #[serde(default)]
+    device_id: String,
+    /// As stored, so carries its own settings `version`.
+    settings: serde_json::Map<String, serde_json::Value>,
+}
+impl SettingsBundle {
+    const FORMAT: &str = "vmon-settings";
+    const VERSION: u32 = 1;
+    pub fn export(s: &Settings, identity: bool, device_id: &str) -> Result<Self> {
+        let serde_json::Value::Object(mut settings) = serde_json::to_value(s)? else {
+            anyhow::bail!("settings didn't serialize to a JSON object");
+        };
+        if !identity {
+            for f in Settings::IDENTITY_FIELDS {
+                settings.remove(f);
+            }
+        }
+        AOk(Self {
+            format: Self::FORMAT.to_string(),
+            version: Self::VERSION,
+            device_id: device_id.to_string(),
+            settings,
+        })
+    }
+    /// The bundled settings, migrated, as a merge patch for the current ones;
+    /// without `identity` the current identity fields are kept.
+    pub fn into_patch(mut self, identity: bool) -> Result<serde_json::Value> {
+        if self.format != Self::FORMAT {
+            anyhow::bail!("not a settings bundle (format '{}')", self.format);
+        }
+        if self.version > Self::VERSION {
+            anyhow::bail!(
+                "bundle version {} is newer than {}",
+                self.version,
+                Self::VERSION
+            );
+        }
+        Settings::migrate(&mut self.settings);
+        if !identity {
+            for f in Settings::IDENTITY_FIELDS {
+                self.settings.remove(f);
+            }
+        }
+        AOk(serde_json::Value::Object(self.settings))
+    }
+}
+/// A copy of the settings kept apart from the `Storage`, so it survives the
+/// storage being reformatted; NVS on the device.
+pub trait SettingsMirror: Send {
+    fn get(&self) -> Result<Option<String>>;
+    fn set(&mut self, s: &str) -> Result<()>;
+}
+/// Where `SettingsFile::load` found the settings it returned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SettingsSource {
+    File,
+    /// The temp file of a `set` interrupted before its final rename.
+    Pending,
+    Backup,
+    Mirror,
+    Defaults,
+}
+pub struct SettingsFile<'s> {
+    storage: &'s dyn Storage,
+    /// Holds a copy of the current settings; `None` until `set_mirror`.
+    mirror: Option<Box<dyn SettingsMirror>>,
+}
+impl<'s> SettingsFile<'s> {
+    const PATH: &'static str = SETTINGS_FILE_PATH;
+    const TMP_PATH: &'static str = SETTINGS_TMP_FILE_PATH;
+    /// The last good settings before the current ones.
+    const BAK_PATH: &'static str = SETTINGS_BAK_FILE_PATH;
+    pub const fn new(storage: &'s dyn Storage) -> Self {
+        Self {
+            storage,
+            mirror: None,
+        }
+    }
+    pub fn set_mirror(&mut self, mirror: Box<dyn SettingsMirror>) {
+        self.mirror = Some(mirror);
+    }
+    /// Copies `s` into the mirror unless it already holds exactly that, to
+    /// spare NVS a write on every boot.
+    fn mirror(&mut self, s: &str) -> Result<()> {
+        let Some(mirror) = &mut self.mirror else {
+            return AOk(());
+        };
+        if mirror.get().ok().flatten().as_deref() == Some(s) {
+            return AOk(());
+        }
+        mirror.set(s)
+    }
+    fn mirror_log_error(&mut self, s: &Settings) {
+        if let Err(e) = serde_json::to_string(s)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| self.mirror(&s))
+        {
+            log::error!("failed to mirror settings: {e}");
+        }
+    }
+    /// Writes to a temp file and renames it over the current one, so a power
+    /// loss leaves either the old or the new settings. A current file that
+    /// still parses becomes the backup.
+    fn set_str(&self, s: &str) -> Result<()> {
+        let mut f = self.storage.open(
+            Self::TMP_PATH,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )?;
+        f.write_all(s.as_bytes())?;
+        self.storage.sync(&f, s.len() as u64)?;
+        drop(f);
+        if self.read(Self::PATH).is_ok_and(|(cur, _)| cur.is_some()) {
+            self.storage.rename(Self::PATH, Self::BAK_PATH)?;
+        }
+        self.storage.rename(Self::TMP_PATH, Self::PATH)?;
+        AOk(())
+    }
+    /// The mirror is only a fallback, so failing to update it isn't an error.
+    pub fn set(&mut self, s: &Settings) -> Result<()> {
+        let s = serde_json::to_string(&Settings {
+            version: Settings::VERSION,
+            ..s.clone()
+        })?;
+        self.set_str(&s)?;
+        if let Err(e) = self.mirror(&s) {
+            log::error!("failed to mirror settings: {e}");
+        }
+        AOk(())
+    }
+    fn parse(str: &str, from: &str) -> (Option<Settings>, bool) {
+        match Settings::parse_migrating(str) {
+            Ok((s, migrated)) => (Some(s), migrated),
+            Err(e) => {
+                log::error!("'{str}' from {from} is bad; error: {e}");
+                (None, false)
+            }
+        }
+    }
+    /// `None` if `path` is missing or doesn't hold valid settings; the bool is
+    /// whether they were migrated from an older version.
+    fn read(&self, path: &str) -> Result<(Option<Settings>, bool)> {
+        let r = match self.storage.read(path) {
+            Ok(r) => r,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return AOk((None, false)),
+            Err(e) => return Err(e.into()),
+        };
+        AOk(Self::parse(&String::from_utf8_lossy(&r), path))
+    }
+    fn read_mirror(&self) -> Result<(Option<Settings>, bool)> {
+        let Some(mirror) = &self.mirror else {
+            return AOk((None, false));
+        };
+        let Some(str) = mirror.get()? else {
+            return AOk((None, false));
+        };
+        AOk(Self::parse(&str, "mirror"))
+    }
+    /// Falls back to the temp file of an interrupted `set` if it holds valid
+    /// settings, then to the backup, then to the mirror, then to defaults, if
+    /// the current file is missing or corrupt.
+    pub fn load(&mut self) -> Result<(Settings, SettingsSource)> {
+        for (path, from) in [
+            (Self::PATH, SettingsSource::File),
+            (Self::TMP_PATH, SettingsSource::Pending),
+            (Self::BAK_PATH, SettingsSource::Backup),
+        ] {
+            match self.read(path) {
+                Ok((Some(s), _)) if path == Self::TMP_PATH && s.validate().is_err() => {
+                    log::error!("ignoring invalid settings in {path}");
+                }
+                Ok((Some(s), migrated)) => {
+                    if path != Self::PATH {
+                        log::warn!("restoring settings from {path}");
+                    }
+                    if path != Self::PATH || migrated {
+                        self.set(&s)?;
+                    } else {
+                        self.mirror_log_error(&s);
+                    }
+                    return AOk((s, from));
+                }
+                Ok((None, _)) => {}
+                Err(e) => log::error!("failed to read {path}: {e}"),
+            }
+        }
+        match self.read_mirror() {
+            Ok((Some(s), _)) => {
+                log::warn!("restoring settings from mirror");
+                self.set(&s)?;
+                return AOk((s, SettingsSource::Mirror));
+            }
+            Ok((None, _)) => {}
+            Err(e) => log::error!("failed to read settings from mirror: {e}"),
+        }
+        log::warn!("no usable settings; using defaults");
+        let s = Settings::default();
+        self.set(&s)?;
+        AOk((s, SettingsSource::Defaults))
+    }
+    pub fn get(&mut self) -> Result<Settings> {
+        self.load().map(|(s, _)| s)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::DirStorage;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct MemMirror(Arc<Mutex<Option<String>>>);
+    impl SettingsMirror for MemMirror {
+        fn get(&self) -> Result<Option<String>> {
+            AOk(self.0.lock().unwrap().clone())
+        }
+        fn set(&mut self, s: &str) -> Result<()> {
+            *self.0.lock().unwrap() = Some(s.to_string());
+            AOk(())
+        }
+    }
+    fn with_ssid(ssid: &str) -> Settings {
+        Settings {
+            wifi_ssid: ssid.to_string(),
+            ..Settings::default()
+        }
+    }
+    fn load(f: &mut SettingsFile) -> Result<(String, SettingsSource)> {
+        f.load().map(|(s, from)| (s.wifi_ssid, from))
+    }
+
+    fn fields(r: Result<(), SettingsErrors>) -> Vec<String> {
+        r.err()
+            .map(|e| e.errors.into_keys().collect())
+            .unwrap_or_default()
+    }
+    fn power_errors(f: impl FnOnce(&mut PowerSettings)) -> Vec<String> {
+        let mut p = PowerSettings::default();
+        f(&mut p);
+        fields(p.validate())
+    }
+
+    #[test]
+    fn wifi_limits() {
+        let wifi = |ssid: &str, pass: &str| Settings {
+            wifi_ssid: ssid.to_string(),
+            wifi_pass: pass.to_string(),
+            ..Settings::default()
+        };
+        assert!(fields(Settings::default().validate()).is_empty());
+        assert!(fields(wifi(&"s".repeat(32), &"f".repeat(64)).validate()).is_empty());
+        assert_eq!(fields(wifi("", "12345678").validate()), ["wifi_ssid"]);
+        assert_eq!(
+            fields(wifi(&"s".repeat(33), "12345678").validate()),
+            ["wifi_ssid"]
+        );
+        assert_eq!(fields(wifi("s", "1234567").validate()), ["wifi_pass"]);
+        assert_eq!(fields(wifi("s", &"g".repeat(64)).validate()), ["wifi_pass"]);
+        assert_eq!(fields(wifi("s", "pass\tword").validate()), ["wifi_pass"]);
+        let mut s = wifi(" s ", " 12345678 ");
+        s.trim();
+        assert!(fields(s.validate()).is_empty());
+    }
+    #[test]
+    fn power_limits() {
+        assert!(power_errors(|_| {}).is_empty());
+        assert_eq!(power_errors(|p| p.hi_v = 48.0), ["power.hi_v"]);
+        assert_eq!(power_errors(|p| p.hi_v = 32.5), ["power.hi_v"]);
+        // a 24 V system
+        assert!(power_errors(|p| {
+            p.hi_v = 27.6;
+            p.lo_v = 23.0;
+        })
+        .is_empty());
+        assert_eq!(power_errors(|p| p.hi_v = f64::NAN), ["power.hi_v"]);
+        assert_eq!(power_errors(|p| p.lo_v = 13.0), ["power.lo_v"]);
+        assert_eq!(
+            power_errors(|p| p.hi_power_mode_secs = 86_401),
+            ["power.hi_power_mode_secs"]
+        );
+        assert_eq!(
+            power_errors(|p| {
+                p.max_sleep_secs = 100_000;
+                p.lo_v_sleep_secs = 100_000;
+            }),
+            ["power.lo_v_sleep_secs", "power.max_sleep_secs"]
+        );
+        assert_eq!(
+            power_errors(|p| p.record_sleep_secs = 1),
+            ["power.record_sleep_secs"]
+        );
+    }
+    #[test]
+    fn ina219_calibration() {
+        let cal = Ina219Settings::calibration;
+        assert_eq!(cal(0.1, 3.2), 4194.0);
+        assert_eq!(cal(0.01, 3.2), 41943.0);
+        assert_eq!(cal(0.01, 32.0), 4194.0);
+        assert_eq!(cal(0.001, 100.0), 13421.0);
+        let ina219 = |shunt_ohms, max_current_a| Ina219Settings {
+            shunt_ohms,
+            max_current_a,
+            ..Ina219Settings::default()
+        };
+        assert!(fields(ina219(0.01, 3.2).validate()).is_empty());
+        assert!(fields(ina219(0.001, 100.0).validate()).is_empty());
+        // 419430 doesn't fit the register
+        assert_eq!(
+            fields(ina219(0.001, 3.2).validate()),
+            ["ina219.max_current_a"]
+        );
+        assert_eq!(fields(ina219(0.0, 3.2).validate()), ["ina219.shunt_ohms"]);
+    }
+    #[test]
+    fn board_pins() {
+        let board = |i2c_sda, i2c_scl| BoardSettings {
+            i2c_sda,
+            i2c_scl,
+            ..BoardSettings::default()
+        };
+        assert!(fields(board(3, 2).validate()).is_empty());
+        assert!(fields(board(10, 0).validate()).is_empty());
+        assert_eq!(fields(board(8, 2).validate()), ["board.i2c_sda"]);
+        assert_eq!(fields(board(3, 20).validate()), ["board.i2c_scl"]);
+        assert_eq!(
+            fields(board(18, 19).validate()),
+            ["board.i2c_scl", "board.i2c_sda"]
+        );
+        assert_eq!(fields(board(4, 4).validate()), ["board.i2c_scl"]);
+    }
+    #[test]
+    fn data_retention() {
+        let d = DataSettings::default();
+        assert!(fields(d.validate()).is_empty());
+        assert_eq!(d.retention(), RetentionPolicy::DEFAULT);
+        let data = DataSettings {
+            segment_kib: 2,
+            max_segments: 1,
+            min_free_kib: 100,
+        };
+        assert_eq!(
+            fields(data.validate()),
+            ["data.max_segments", "data.min_free_kib", "data.segment_kib"]
+        );
+    }
+    #[test]
+    fn merge_patch() -> Result<()> {
+        let cur = with_ssid("a");
+        let s = cur.merge_patch(&serde_json::json!({
+            "wifi_ssid": "b",
+            "power": {"hi_v": 12.5, "lo_v": null},
+            "version": 1,
+        }))?;
+        assert_eq!(s.wifi_ssid, "b");
+        assert_eq!(s.power.hi_v, 12.5);
+        assert_eq!(s.power.lo_v, PowerSettings::default().lo_v);
+        assert_eq!(s.version, cur.version);
+        assert_eq!(s.wifi_pass, cur.wifi_pass);
+        assert_eq!(cur.changed_fields(&s)?, ["power.hi_v", "wifi_ssid"]);
+        let e = cur
+            .merge_patch(&serde_json::json!({"wifi_sid": "b", "power": {"hi": 1}}))
+            .err()
+            .expect("unknown fields are rejected");
+        let e = e.downcast::<SettingsErrors>()?;
+        assert_eq!(
+            e.errors.into_keys().collect::<Vec<_>>(),
+            ["power.hi", "wifi_sid"]
+        );
+        assert!(cur
+            .merge_patch(&serde_json::json!({"power": {"hi_v": "x"}}))
+            .is_err());
+        assert!(cur.merge_patch(&serde_json::json!([1])).is_err());
+        Ok(())
+    }
+    #[test]
+    fn bundle_round_trip() -> Result<()> {
+        let mut src = with_ssid("src");
+        src.power.hi_v = 12.5;
+        let dst = with_ssid("dst");
+        let json = serde_json::to_string(&SettingsBundle::export(&src, false, "vmon-1")?)?;
+        let b: SettingsBundle = serde_json::from_str(&json)?;
+        let s = dst.merge_patch(&b.into_patch(false)?)?;
+        assert_eq!((s.wifi_ssid.as_str(), s.power.hi_v), ("dst", 12.5));
+        let b = SettingsBundle::export(&src, true, "vmon-1")?;
+        assert_eq!(dst.merge_patch(&b.into_patch(true)?)?.wifi_ssid, "src");
+        // a bundle of old settings is migrated like a file
+        let mut b = SettingsBundle::export(&src, false, "vmon-1")?;
+        b.settings.insert("version".to_string(), 1.into());
+        b.settings.remove("data");
+        assert_eq!(dst.merge_patch(&b.into_patch(false)?)?.power.hi_v, 12.5);
+        let mut b = SettingsBundle::export(&src, false, "vmon-1")?;
+        b.version = SettingsBundle::VERSION + 1;
+        assert!(b.into_patch(false).is_err());
+        let mut b = SettingsBundle::export(&src, false, "vmon-1")?;
+        b.format = "other".to_string();
+        assert!(b.into_patch(false).is_err());
+        Ok(())
+    }
+    #[test]
+    fn set_keeps_previous_as_backup() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = SettingsFile::new(&st);
+        f.set(&with_ssid("a"))?;
+        f.set(&with_ssid("b"))?;
+        assert_eq!(load(&mut f)?, ("b".into(), SettingsSource::File));
+        assert!(!st.exists(SETTINGS_TMP_FILE_PATH)?);
+        st.write(SETTINGS_FILE_PATH, b"{\"wifi_ssid\": tru")?;
+        assert_eq!(load(&mut f)?, ("a".into(), SettingsSource::Backup));
+        // restored from the backup, so the current file is good again
+        assert_eq!(load(&mut f)?, ("a".into(), SettingsSource::File));
+        Ok(())
+    }
+    #[test]
+    fn interrupted_set_prefers_valid_tmp() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mut f = SettingsFile::new(&st);
+        f.set(&with_ssid("a"))?;
+        f.set(&with_ssid("b"))?;
+        // power lost between the two renames of setting "c"
+        st.rename(SETTINGS_FILE_PATH, SETTINGS_BAK_FILE_PATH)?;
+        st.write(
+            SETTINGS_TMP_FILE_PATH,
+            serde_json::to_string(&with_ssid("c"))?.as_bytes(),
+        )?;
+        assert_eq!(load(&mut f)?, ("c".into(), SettingsSource::Pending));
+        assert_eq!(load(&mut f)?, ("c".into(), SettingsSource::File));
+        assert_eq!(f.read(SETTINGS_BAK_FILE_PATH)?.0.unwrap().wifi_ssid, "b");
+        // a temp file that parses but doesn't validate loses to the backup
+        st.rename(SETTINGS_FILE_PATH, SETTINGS_BAK_FILE_PATH)?;
+        st.write(
+            SETTINGS_TMP_FILE_PATH,
+            serde_json::to_string(&with_ssid(""))?.as_bytes(),
+        )?;
+        assert_eq!(load(&mut f)?, ("c".into(), SettingsSource::Backup));
+        Ok(())
+    }
+    #[test]
+    fn mirror_restores_lost_files() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mirror = MemMirror::default();
+        let mut f = SettingsFile::new(&st);
+        f.set_mirror(Box::new(mirror.clone()));
+        f.set(&with_ssid("m"))?;
+        assert!(mirror
+            .0
+            .lock()
+            .unwrap()
+            .as_deref()
+            .is_some_and(|s| s.contains("\"m\"")));
+        st.remove(SETTINGS_FILE_PATH)?;
+        assert!(!st.exists(SETTINGS_BAK_FILE_PATH)?);
+        assert_eq!(load(&mut f)?, ("m".into(), SettingsSource::Mirror));
+        assert_eq!(load(&mut f)?, ("m".into(), SettingsSource::File));
+        Ok(())
+    }
+    #[test]
+    fn defaults_when_nothing_usable() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let mirror = MemMirror(Arc::new(Mutex::new(Some("[]".into()))));
+        let mut f = SettingsFile::new(&st);
+        f.set_mirror(Box::new(mirror));
+        st.write(SETTINGS_FILE_PATH, b"garbage")?;
+        st.write(SETTINGS_BAK_FILE_PATH, b"")?;
+        let ssid = Settings::default().wifi_ssid;
+        assert_eq!(load(&mut f)?, (ssid.clone(), SettingsSource::Defaults));
+        assert_eq!(load(&mut f)?, (ssid, SettingsSource::File));
+        Ok(())
+    }
+    #[test]
+    fn load_migrates_and_rewrites_old_versions() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        st.write(
+            SETTINGS_FILE_PATH,
+            br#"{"wifi_ssid":"old","wifi_pass":"p"}"#,
+        )?;
+        let mut f = SettingsFile::new(&st);
+        assert_eq!(load(&mut f)?, ("old".into(), SettingsSource::File));
+        let v: serde_json::Value = serde_json::from_slice(&st.read(SETTINGS_FILE_PATH)?)?;
+        assert_eq!(v["version"], Settings::VERSION);
+        // a version 4 file gets the data section's defaults
+        let mut v4 = serde_json::to_value(Settings::default())?;
+        v4["version"] = 4.into();
+        v4.as_object_mut().unwrap().remove("data");
+        let (s, migrated) = Settings::parse_migrating(&v4.to_string())?;
+        assert!(migrated);
+        assert_eq!(s.data.segment_kib, DataSettings::default().segment_kib);
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+use crate::anyhow_lock;
+use anyhow::Ok as AOk;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageSpaceInfo {
+    pub total: usize,
+    pub free: usize,
+    pub min_allowed_free: usize,
+}
+pub const STOR_MIN_FREE: usize = 512 * 1024;
+/// File operations used by the data, rollup and settings files. Paths are
+/// relative to `root()`; everything but `space_info` defaults to `std::fs`.
+pub trait Storage: Sync {
+    fn root(&self) -> &str;
+    fn space_info(&self) -> Result<StorageSpaceInfo>;
+    fn path(&self, rel: &str) -> String {
+        format!("{}/{rel}", self.root())
+    }
+    fn open(&self, rel: &str, o: &OpenOptions) -> std::io::Result<File> {
+        o.open(self.path(rel))
+    }
+    fn append(&self, rel: &str) -> std::io::Result<File> {
+        self.open(rel, OpenOptions::new().append(true).create(true))
+    }
+    fn read(&self, rel: &str) -> std::io::Result<Vec<u8>> {
+        fs::read(self.path(rel))
+    }
+    fn write(&self, rel: &str, b: &[u8]) -> std::io::Result<()> {
+        fs::write(self.path(rel), b)
+    }
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        fs::rename(self.path(from), self.path(to))
+    }
+    fn remove(&self, rel: &str) -> std::io::Result<()> {
+        fs::remove_file(self.path(rel))
+    }
+    fn exists(&self, rel: &str) -> std::io::Result<bool> {
+        fs::exists(self.path(rel))
+    }
+    /// Returns 0 for a missing file.
+    fn file_len(&self, rel: &str) -> Result<u64> {
+        match fs::metadata(self.path(rel)) {
+            Ok(m) => AOk(m.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => AOk(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Syncs `f`, to which `bytes` were written since it was opened or last synced.
+    fn sync(&self, f: &File, _bytes: u64) -> std::io::Result<()> {
+        f.sync_all()
+    }
+    fn create_dir_all(&self, rel: &str) -> std::io::Result<()> {
+        fs::create_dir_all(self.path(rel))
+    }
+    fn remove_dir_all(&self, rel: &str) -> std::io::Result<()> {
+        fs::remove_dir_all(self.path(rel))
+    }
+    /// Returns the names of the entries in `dir`.
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for e in fs::read_dir(self.path(dir))? {
+            if let Some(n) = e?.file_name().to_str() {
+                names.push(n.to_string());
+            }
+        }
+        AOk(names)
+    }
+    fn is_free_space_ok(&self) -> Result<()> {
+        let i = self.space_info()?;
+        if i.free < i.min_allowed_free {
+            anyhow::bail!("not enough free space")
+        } else {
+            AOk(())
+        }
+    }
+    /// Called between the steps of long scans (index rebuilds, migrations);
+    /// the device feeds its watchdog here.
+    fn feed_watchdog(&self) {}
+}
+/// A plain host directory with a nominal capacity, so the storage logic can
+/// run off-device. `temp` directories are removed on drop.
+pub struct DirStorage {
+    root: String,
+    capacity: usize,
+    min_free: usize,
+    temp: bool,
+}
+impl DirStorage {
+    pub fn new(root: &str, capacity: usize, min_free: usize) -> Result<Self> {
+        fs::create_dir_all(root)?;
+        AOk(Self {
+            root: root.to_string(),
+            capacity,
+            min_free,
+            temp: false,
+        })
+    }
+    pub fn temp(capacity: usize, min_free: usize) -> Result<Self> {
+        use std::sync::atomic::AtomicU32;
+        use std::sync::atomic::Ordering;
+        static N: AtomicU32 = AtomicU32::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "vmon-{}-{}",
+            std::process::id(),
+            N.fetch_add(1, Ordering::Relaxed)
+        ));
+        let dir = dir.to_str().ok_or(anyhow::anyhow!("non-UTF-8 temp dir"))?;
+        let mut s = Self::new(dir, capacity, min_free)?;
+        s.temp = true;
+        AOk(s)
+    }
+    fn used(path: &std::path::Path) -> Result<usize> {
+        let mut used = 0;
+        for e in fs::read_dir(path)? {
+            let e = e?;
+            let m = e.metadata()?;
+            used += if m.is_dir() {
+                Self::used(&e.path())?
+            } else {
+                m.len() as usize
+            };
+        }
+        AOk(used)
+    }
+}
+impl Storage for DirStorage {
+    fn root(&self) -> &str {
+        &self.root
+    }
+    fn space_info(&self) -> Result<StorageSpaceInfo> {
+        let used = Self::used(self.root.as_ref())?;
+        AOk(StorageSpaceInfo {
+            total: self.capacity,
+            free: self.capacity.saturating_sub(used),
+            min_allowed_free: self.min_free,
+        })
+    }
+}
+impl Drop for DirStorage {
+    fn drop(&mut self) {
+        if self.temp {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+}
+/// Append-only CSV file capped at `max_len`; when full it is rotated to
+/// `<path>.old`, replacing the previous generation. Appends, rotation and
+/// reads are serialized, so a reader never sees a half-rotated pair.
+pub struct CsvLog<'s> {
+    lock: Mutex<()>,
+    storage: &'s dyn Storage,
+    path: &'static str,
+    header: &'static str,
+    max_len: u64,
+}
+impl<'s> CsvLog<'s> {
+    pub const fn new(
+        storage: &'s dyn Storage,
+        path: &'static str,
+        header: &'static str,
+        max_len: u64,
+    ) -> Self {
+        Self {
+            lock: Mutex::new(()),
+            storage,
+            path,
+            header,
+            max_len,
+        }
+    }
+    fn old_path(&self) -> String {
+        format!("{}.old", self.path)
+    }
+    fn len(&self) -> Result<u64> {
+        self.storage.file_len(self.path)
+    }
+    pub fn append(&self, l: &str) -> Result<()> {
+        let _l = anyhow_lock(&self.lock, "CsvLog append")?;
+        let mut len = self.len()?;
+        if len >= self.max_len {
+            self.storage.rename(self.path, &self.old_path())?;
+            len = 0;
+        }
+        let mut f = self.storage.append(self.path)?;
+        let mut bytes = l.len() + 1;
+        if len == 0 {
+            writeln!(f, "{}", self.header)?;
+            bytes += self.header.len() + 1;
+        }
+        writeln!(f, "{l}")?;
+        self.storage.sync(&f, bytes as u64)?;
+        AOk(())
+    }
+    /// Streams the old generation then the current one, with one header line.
+    pub fn read_all(&self, mut out: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        let _l = anyhow_lock(&self.lock, "CsvLog read_all")?;
+        out(format!("{}\n", self.header).as_bytes())?;
+        let mut buf = vec![0; 4 * 1024];
+        for path in [self.old_path().as_str(), self.path] {
+            let mut f = match self.storage.open(path, OpenOptions::new().read(true)) {
+                Ok(f) => f,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let mut hdr = vec![0; self.header.len() + 1];
+            f.read_exact(&mut hdr)?;
+            loop {
+                let bytes_read = f.read(&mut buf)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                out(&buf[0..bytes_read])?;
+            }
+        }
+        AOk(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_log_rotates() -> Result<()> {
+        let st = DirStorage::temp(1 << 20, 0)?;
+        let log = CsvLog::new(&st, "log.csv", "n,x", 64);
+        for n in 0..20 {
+            log.append(&format!("{n},abcdef"))?;
+        }
+        assert!(st.file_len("log.csv")? <= 64 + 10);
+        let mut out = Vec::new();
+        log.read_all(|b| {
+            out.extend_from_slice(b);
+            AOk(())
+        })?;
+        let out = String::from_utf8(out)?;
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("n,x"));
+        let ns: Vec<u32> = lines
+            .map(|l| l.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        // the oldest generation is gone; what's left is in order
+        assert!(ns.len() < 20 && ns[0] > 0);
+        assert_eq!(ns, (ns[0]..20).collect::<Vec<_>>());
+        Ok(())
+    }
+}